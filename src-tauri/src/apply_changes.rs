@@ -1,22 +1,295 @@
-use crate::apply_file_change::apply_file_change;
-use crate::change_types::{FileError, FileSuccess};
-use crate::parse_change_protocol::parse_change_protocol;
-use anyhow::{Context, Result};
+use crate::apply_file_change::{
+    apply_file_change, change_results, normalize_path, resolve_file_path, resolve_plan_paths,
+    resolve_target_path,
+};
+use crate::change_types::{
+    Action, ApplyOptions, ApplyReport, BlockedFile, ChangeStatus, FileChange, FileError,
+    FileOutcome, FileSuccess, StaleFile,
+};
+use crate::dir_actions::list_tree;
+use crate::extract_plans::select_plan;
+use crate::git_utils::stage_rename;
+use crate::hash_utils::{content_hash, hash_matches};
+use crate::journal::{Journal, JournalEntry};
+use crate::parse_plan::parse_plan;
+use crate::sandbox_policy::SandboxPolicy;
+use crate::select_changes::select_changes;
+use crate::transaction::Transaction;
+use anyhow::{anyhow, Context, Result};
 use sentry;
-use std::env;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
 
-pub fn apply_changes(xml_protocol: &str) -> Result<(Vec<FileSuccess>, Vec<FileError>)> {
-    let cwd = env::current_dir().context("Failed to get current working directory")?;
-    log::debug!("Current working directory: {}", cwd.display());
+pub fn apply_changes(
+    xml_protocol: &str,
+    options: &ApplyOptions,
+    journal: Option<&Journal>,
+) -> Result<ApplyReport> {
+    let project_root = options
+        .project_root
+        .as_deref()
+        .map(normalize_path)
+        .ok_or_else(|| anyhow!("Plan rejected: no project root was provided"))?;
+    log::debug!("Project root: {}", project_root.display());
 
-    let parsed = parse_change_protocol(xml_protocol)
-        .context("Failed to parse the change management protocol XML")?;
+    let selected = select_plan(xml_protocol, options.plan_index)?;
+    let plan =
+        parse_plan(&selected).context("Failed to parse the change management protocol XML")?;
+    let parsed = select_changes(plan.file_changes, options.include.as_deref())?;
+    let warnings = plan.warnings;
     log::debug!("Parsed {} FileChange entries: {:#?}", parsed.len(), parsed);
 
-    let mut file_success = Vec::new();
-    let mut file_errors = Vec::new();
+    let resolved = resolve_plan_paths(&parsed, Some(&project_root)).context("Plan rejected")?;
 
-    for fc in parsed {
+    let policy = SandboxPolicy::load(&project_root)?;
+    let confirmed = confirmed_set(options, &project_root);
+    let (parsed, resolved, blocked) =
+        enforce_policy(&policy, &project_root, parsed, resolved, &confirmed)?;
+
+    if options.transactional && !blocked.is_empty() {
+        let first = &blocked[0];
+        return Ok(ApplyReport {
+            aborted: Some(FileError {
+                path: first.path.clone(),
+                messages: vec![
+                    "Transaction aborted: the plan touches protected paths that were not confirmed"
+                        .to_string(),
+                    first.reason.clone(),
+                ],
+                changes: Vec::new(),
+            }),
+            blocked,
+            warnings,
+            ..Default::default()
+        });
+    }
+
+    let (stale, stale_paths) = find_stale_files(&parsed, &resolved);
+    let (parsed, resolved) = if options.refuse_stale && !stale.is_empty() {
+        if options.transactional {
+            let first = &stale[0];
+            return Ok(ApplyReport {
+                aborted: Some(FileError {
+                    path: first.path.clone(),
+                    messages: vec![
+                        "Transaction aborted: file changed since the prompt was generated"
+                            .to_string(),
+                    ],
+                    changes: Vec::new(),
+                }),
+                blocked,
+                stale,
+                warnings,
+                ..Default::default()
+            });
+        }
+        parsed
+            .into_iter()
+            .zip(resolved)
+            .filter(|(_, path)| !stale_paths.contains(path))
+            .unzip()
+    } else {
+        (parsed, resolved)
+    };
+
+    let targets: Vec<Option<PathBuf>> = parsed
+        .iter()
+        .map(|fc| resolve_target_path(fc, Some(&project_root)))
+        .collect::<Result<_>>()?;
+    let mut touched: Vec<PathBuf> = Vec::new();
+    for ((fc, path), target) in parsed.iter().zip(&resolved).zip(&targets) {
+        let listing = match fc.action {
+            // Only files: undo recreates their directories and must never remove one
+            Action::DeleteDir | Action::Rename if path.is_dir() => list_tree(path)
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|entry| !path.join(entry).is_dir())
+                .collect(),
+            _ => Vec::new(),
+        };
+        touched.extend(written_paths(fc, path, target.as_ref(), &listing));
+    }
+    let pending = journal.and_then(|journal| begin_journal(journal, xml_protocol, &touched));
+
+    let mut report = if options.transactional {
+        apply_transaction(&parsed, &resolved, options)
+    } else {
+        apply_each(&parsed, &resolved, options)
+    };
+    report.blocked = blocked;
+    report.stale = stale;
+
+    let mut applied: HashSet<PathBuf> = HashSet::new();
+    for ((fc, from), to) in parsed.iter().zip(&resolved).zip(&targets) {
+        let Some(success) = report.success.iter().find(|s| s.index == fc.index) else {
+            continue;
+        };
+        applied.extend(written_paths(fc, from, to.as_ref(), &success.listing));
+        let (Action::Rename, Some(to)) = (&fc.action, to) else {
+            continue;
+        };
+        // Best effort: the move already happened on disk either way
+        let note = match stage_rename(&project_root, from, to) {
+            Ok(true) => Some("Rename staged in git".to_string()),
+            Ok(false) => None,
+            Err(err) => {
+                log::warn!("Could not stage rename in git: {:#}", err);
+                Some(format!("Could not stage rename in git: {:#}", err))
+            }
+        };
+        if let Some(note) = note {
+            if let Some(success) = report.success.iter_mut().find(|s| s.index == fc.index) {
+                success.messages.push(note);
+            }
+        }
+    }
+
+    if let (Some(journal), Some(entry)) = (journal, pending) {
+        match journal.finish(entry, &applied) {
+            Ok(id) => report.journal_id = id,
+            Err(err) => {
+                log::error!("Failed to finalize apply journal: {:#}", err);
+                sentry::capture_error(err.root_cause());
+            }
+        }
+    }
+
+    report.warnings = warnings;
+    Ok(report)
+}
+
+// Paths a FileChange writes, i.e. what the journal must snapshot to undo it. A copy
+// leaves its source alone; a delete-dir writes every entry of its listing.
+fn written_paths(
+    fc: &FileChange,
+    path: &Path,
+    target: Option<&PathBuf>,
+    listing: &[PathBuf],
+) -> Vec<PathBuf> {
+    match fc.action {
+        Action::Copy => target.into_iter().cloned().collect(),
+        Action::DeleteDir => listing.iter().map(|entry| path.join(entry)).collect(),
+        // A moved directory: every entry leaves one place and appears in the other
+        Action::Rename if !listing.is_empty() => listing
+            .iter()
+            .flat_map(|entry| {
+                std::iter::once(path.join(entry)).chain(target.map(|to| to.join(entry)))
+            })
+            .collect(),
+        _ => std::iter::once(path.to_path_buf())
+            .chain(target.cloned())
+            .collect(),
+    }
+}
+
+// Compares each `### Hash` with the file on disk before anything is written, so every
+// FileChange is checked against the state the model actually saw.
+fn find_stale_files(
+    parsed: &[FileChange],
+    resolved: &[PathBuf],
+) -> (Vec<StaleFile>, HashSet<PathBuf>) {
+    let mut stale = Vec::new();
+    let mut stale_paths = HashSet::new();
+    for (fc, path) in parsed.iter().zip(resolved) {
+        let Some(expected) = &fc.hash else {
+            continue;
+        };
+        if stale_paths.contains(path) {
+            continue;
+        }
+        let actual = fs::read(path).ok().map(|bytes| content_hash(&bytes));
+        if actual
+            .as_ref()
+            .is_some_and(|hash| hash_matches(expected, hash))
+        {
+            continue;
+        }
+        log::debug!("Stale file: {} (expected {})", path.display(), expected);
+        stale.push(StaleFile {
+            path: fc.path.clone(),
+            expected: expected.clone(),
+            actual: actual.map(|hash| hash[..expected.len().min(hash.len())].to_string()),
+        });
+        stale_paths.insert(path.clone());
+    }
+    (stale, stale_paths)
+}
+
+// Protected paths the caller has confirmed, resolved like the plan's own paths
+pub fn confirmed_set(options: &ApplyOptions, project_root: &Path) -> HashSet<PathBuf> {
+    options
+        .confirmed_paths
+        .iter()
+        .filter_map(|p| resolve_file_path(p, Some(project_root)).ok())
+        .collect()
+}
+
+// Symlink escapes reject the whole plan; protected paths are held back (and reported)
+// unless the caller confirmed them explicitly. Rename and copy targets are held to the
+// same rules, and a directory action is blocked by anything protected inside it.
+fn enforce_policy(
+    policy: &SandboxPolicy,
+    project_root: &Path,
+    parsed: Vec<FileChange>,
+    resolved: Vec<PathBuf>,
+    confirmed: &HashSet<PathBuf>,
+) -> Result<(Vec<FileChange>, Vec<PathBuf>, Vec<BlockedFile>)> {
+    let mut allowed_changes = Vec::new();
+    let mut allowed_paths = Vec::new();
+    let mut blocked = Vec::new();
+    for (fc, path) in parsed.into_iter().zip(resolved) {
+        let target = resolve_target_path(&fc, Some(project_root))?;
+        if let Action::DeleteDir = fc.action {
+            if path == project_root {
+                return Err(anyhow!(
+                    "Plan rejected: refusing to delete the project root"
+                ));
+            }
+        }
+        for touched in std::iter::once(&path).chain(target.as_ref()) {
+            policy.check_containment(touched).context("Plan rejected")?;
+        }
+        if let Some((touched, reason)) =
+            policy.unconfirmed_protected(&fc.action, &path, target.as_deref(), confirmed)?
+        {
+            log::debug!("Blocked protected path: {}", touched.display());
+            blocked.push(BlockedFile {
+                id: fc.id(),
+                path: fc.path.clone(),
+                reason,
+                confirm: std::iter::once(path).chain(target).collect(),
+            });
+            continue;
+        }
+        allowed_changes.push(fc);
+        allowed_paths.push(path);
+    }
+    Ok((allowed_changes, allowed_paths, blocked))
+}
+
+// Journaling is best effort: a failure here is reported but never blocks the apply
+fn begin_journal(journal: &Journal, plan: &str, resolved: &[PathBuf]) -> Option<JournalEntry> {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    for path in resolved {
+        if !paths.contains(path) {
+            paths.push(path.clone());
+        }
+    }
+    match journal.begin(plan, &paths) {
+        Ok(entry) => Some(entry),
+        Err(err) => {
+            log::error!("Failed to record apply journal: {:#}", err);
+            sentry::capture_error(err.root_cause());
+            None
+        }
+    }
+}
+
+fn apply_each(parsed: &[FileChange], resolved: &[PathBuf], options: &ApplyOptions) -> ApplyReport {
+    let mut report = ApplyReport::default();
+
+    for (fc, resolved_path) in parsed.iter().zip(resolved) {
         log::debug!(
             "Applying FileChange: path={}, action={:?}",
             fc.path.display(),
@@ -24,30 +297,277 @@ pub fn apply_changes(xml_protocol: &str) -> Result<(Vec<FileSuccess>, Vec<FileEr
         );
 
         // 1) call the file-change fn, 2) if it Errs, wrap it once with your file path
-        let result = apply_file_change(&fc)
+        let result = apply_file_change(fc, resolved_path, options)
             .map_err(|e| e.context(format!("While applying change to '{}'", fc.path.display())));
 
         match result {
-            Ok(_) => {
-                file_success.push(FileSuccess {
-                    path: fc.path.clone(),
-                    messages: vec!["Success".to_string()],
-                });
+            Ok(outcome) => {
+                report.success.push(file_success(fc, outcome));
             }
             Err(err) => {
-                // report to Sentry that root_cause, but keep the full chain for our payload
-                sentry::capture_error(err.root_cause());
+                report.errors.push(file_error(fc, &err));
+            }
+        }
+    }
+
+    report
+}
 
-                // Gather *all* layers of the chain into strings
-                let messages: Vec<String> = err.chain().map(|cause| cause.to_string()).collect();
+fn apply_transaction(
+    parsed: &[FileChange],
+    resolved: &[PathBuf],
+    options: &ApplyOptions,
+) -> ApplyReport {
+    let mut report = ApplyReport::default();
+    let mut transaction = Transaction::new();
+    let mut staged_results = Vec::new();
 
-                file_errors.push(FileError {
-                    path: fc.path.clone(),
-                    messages,
-                });
+    for (fc, resolved_path) in parsed.iter().zip(resolved) {
+        log::debug!(
+            "Staging FileChange: path={}, action={:?}",
+            fc.path.display(),
+            fc.action
+        );
+        match transaction.stage(fc, resolved_path, options) {
+            Ok(outcome) => staged_results.push(outcome),
+            Err(err) => {
+                let err = err.context(format!(
+                    "Transaction aborted while staging '{}'",
+                    fc.path.display()
+                ));
+                let error = file_error(fc, &err);
+                report.errors.push(error.clone());
+                report.aborted = Some(error);
+                return report;
             }
         }
     }
 
-    Ok((file_success, file_errors))
+    match transaction.commit() {
+        Ok(()) => {
+            report.success = parsed
+                .iter()
+                .zip(staged_results)
+                .map(|(fc, outcome)| file_success(fc, outcome))
+                .collect();
+        }
+        Err((path, err)) => {
+            let err = err.context(format!(
+                "Transaction aborted while writing '{}'; all changes were rolled back",
+                path.display()
+            ));
+            sentry::capture_error(err.root_cause());
+            let error = FileError {
+                path,
+                messages: err.chain().map(|cause| cause.to_string()).collect(),
+                changes: Vec::new(),
+            };
+            report.errors.push(error.clone());
+            report.aborted = Some(error);
+        }
+    }
+    report
+}
+
+fn file_error(fc: &FileChange, err: &anyhow::Error) -> FileError {
+    // report to Sentry that root_cause, but keep the full chain for our payload
+    sentry::capture_error(err.root_cause());
+
+    // Gather *all* layers of the chain into strings
+    let messages: Vec<String> = err.chain().map(|cause| cause.to_string()).collect();
+
+    FileError {
+        path: fc.path.clone(),
+        messages,
+        changes: change_results(err),
+    }
+}
+
+fn file_success(fc: &FileChange, outcome: FileOutcome) -> FileSuccess {
+    let FileOutcome { changes, listing } = outcome;
+    let skipped = changes
+        .iter()
+        .filter(|c| c.status == ChangeStatus::Skipped)
+        .count();
+    let message = if skipped == 0 {
+        "Success".to_string()
+    } else {
+        format!(
+            "Applied {} of {} changes; skipped the rest",
+            changes.len() - skipped,
+            changes.len()
+        )
+    };
+    FileSuccess {
+        index: fc.index,
+        path: fc.path.clone(),
+        messages: vec![message],
+        changes,
+        listing,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch_dir;
+
+    fn options(root: &Path) -> ApplyOptions {
+        ApplyOptions {
+            project_root: Some(root.to_path_buf()),
+            ..Default::default()
+        }
+    }
+
+    fn hashed_plan(hash: &str) -> String {
+        format!(
+            "### File a.txt\n### Action modify\n### Hash {}\n#### Change\n**Search**:\n```\none\n```\n**Content**:\n```\n1\n```\n",
+            hash
+        )
+    }
+
+    #[test]
+    fn stale_files_are_reported_and_refused_on_request() {
+        let root = scratch_dir("apply-stale");
+        fs::write(root.join("a.txt"), "one\n").unwrap();
+        let seen = crate::hash_utils::short_hash(b"one\n");
+        let report = apply_changes(&hashed_plan(&seen), &options(&root), None).unwrap();
+        assert!(report.stale.is_empty());
+        assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "1\n");
+
+        let refuse = ApplyOptions {
+            refuse_stale: true,
+            ..options(&root)
+        };
+        let report = apply_changes(&hashed_plan(&seen), &refuse, None).unwrap();
+        assert_eq!(report.stale.len(), 1);
+        assert_eq!(report.stale[0].expected, seen);
+        assert!(report.success.is_empty());
+        assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "1\n");
+    }
+
+    #[test]
+    fn short_hashes_are_rejected() {
+        let root = scratch_dir("apply-short-hash");
+        fs::write(root.join("a.txt"), "one\n").unwrap();
+        let prefix = &crate::hash_utils::short_hash(b"one\n")[..1];
+        assert!(apply_changes(&hashed_plan(prefix), &options(&root), None).is_err());
+        assert!(apply_changes(&hashed_plan("not-hex!"), &options(&root), None).is_err());
+        assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "one\n");
+    }
+
+    #[test]
+    fn copying_a_directory_with_protected_files_is_blocked() {
+        let root = scratch_dir("apply-copy-protected");
+        fs::create_dir_all(root.join("conf")).unwrap();
+        fs::write(root.join("conf/.env"), "SECRET=1\n").unwrap();
+        let plan = "### File conf\n### Action copy\n### To public/conf\n";
+
+        let report = apply_changes(plan, &options(&root), None).unwrap();
+        assert_eq!(report.blocked.len(), 1);
+        assert!(report.blocked[0].reason.contains(".env"));
+        assert!(!root.join("public").exists());
+
+        assert_eq!(report.blocked[0].id, "0");
+        assert_eq!(
+            report.blocked[0].confirm,
+            vec![root.join("conf"), root.join("public/conf")]
+        );
+
+        // Resending what the report asks to confirm lets the copy through
+        let confirmed = ApplyOptions {
+            include: Some(vec![report.blocked[0].id.clone()]),
+            confirmed_paths: report.blocked[0].confirm.clone(),
+            ..options(&root)
+        };
+        let report = apply_changes(plan, &confirmed, None).unwrap();
+        assert!(report.blocked.is_empty(), "{:?}", report.blocked);
+        assert!(root.join("public/conf/.env").exists());
+    }
+
+    #[test]
+    fn selective_apply_reports_excluded_changes_and_refuses_overlaps() {
+        let root = scratch_dir("apply-selective");
+        fs::write(root.join("a.txt"), "one\ntwo\nthree\n").unwrap();
+        let plan = "### File a.txt\n### Action modify\n#### Change\n**Search**:\n```\none\n```\n**Content**:\n```\n1\n```\n#### Change\n**Search**:\n```\nthree\n```\n**Content**:\n```\n3\n```\n#### Change\n**Search**:\n```\none\ntwo\n```\n**Content**:\n```\n1 and 2\n```\n";
+        let selected = |ids: &[&str]| ApplyOptions {
+            include: Some(ids.iter().map(|id| id.to_string()).collect()),
+            ..options(&root)
+        };
+
+        let report = apply_changes(plan, &selected(&["0:0"]), None).unwrap();
+        assert!(report.success.is_empty());
+        assert_eq!(report.errors.len(), 1);
+        assert!(report.errors[0].messages.join("\n").contains("0:2"));
+        assert_eq!(
+            fs::read_to_string(root.join("a.txt")).unwrap(),
+            "one\ntwo\nthree\n"
+        );
+
+        let report = apply_changes(plan, &selected(&["0:1"]), None).unwrap();
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        let statuses: Vec<_> = report.success[0]
+            .changes
+            .iter()
+            .map(|change| (change.id.as_str(), change.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("0:0", ChangeStatus::Excluded),
+                ("0:1", ChangeStatus::Applied),
+                ("0:2", ChangeStatus::Excluded)
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("a.txt")).unwrap(),
+            "one\ntwo\n3\n"
+        );
+    }
+
+    #[test]
+    fn transactional_apply_aborts_without_writing() {
+        let root = scratch_dir("apply-transactional");
+        fs::write(root.join("a.txt"), "one\n").unwrap();
+        let plan = "### File b.txt\n### Action create\n#### Change\n**Content**:\n```\nb\n```\n### File a.txt\n### Action modify\n#### Change\n**Search**:\n```\nmissing\n```\n**Content**:\n```\nx\n```\n";
+        let transactional = ApplyOptions {
+            transactional: true,
+            ..options(&root)
+        };
+        let report = apply_changes(plan, &transactional, None).unwrap();
+        let aborted = report.aborted.expect("the apply should abort");
+        assert_eq!(aborted.path, PathBuf::from("a.txt"));
+        assert!(report.success.is_empty());
+        assert!(report.journal_id.is_none());
+        assert!(!root.join("b.txt").exists());
+        assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "one\n");
+    }
+
+    #[test]
+    fn results_are_matched_by_file_change_not_path() {
+        let root = scratch_dir("apply-same-path");
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .args(args)
+                .current_dir(&root)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        git(&["init", "-q"]);
+        fs::write(root.join("a.txt"), "one\n").unwrap();
+        git(&["add", "a.txt"]);
+        let plan = "### File a.txt\n### Action modify\n#### Change\n**Search**:\n```\none\n```\n**Content**:\n```\n1\n```\n### File a.txt\n### Action rename\n### To b.txt\n";
+        let report = apply_changes(plan, &options(&root), None).unwrap();
+        assert!(report.errors.is_empty(), "{:?}", report.errors);
+        let messages: Vec<_> = report.success.iter().map(|s| &s.messages).collect();
+        assert_eq!(
+            messages,
+            vec![
+                &vec!["Success".to_string()],
+                &vec!["Success".to_string(), "Rename staged in git".to_string()]
+            ]
+        );
+        assert_eq!(fs::read_to_string(root.join("b.txt")).unwrap(), "1\n");
+    }
 }
@@ -1,150 +1,944 @@
-use crate::change_types::{Action, Change, FileChange};
+use crate::atomic_write::{atomic_write, file_mode, set_mode};
+use crate::change_types::{
+    change_id, Action, ApplyOptions, Change, ChangeResult, ChangeStatus, FileChange, FileOutcome,
+    FinalNewline, LineRange, Occurrence,
+};
+use crate::dir_actions::{copy_path, create_dir, delete_dir, move_path};
+use crate::editorconfig::insert_final_newline;
+use crate::fuzzy_match::{
+    closest_candidate, describe_closest, find_candidates, DEFAULT_FUZZY_THRESHOLD,
+};
+use crate::text_encoding::TextFormat;
 use anyhow::{anyhow, Context, Result};
 use log::debug;
 use regex::escape as regex_escape;
 use regex::Regex;
 use sentry;
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
-fn resolve_file_path(path: &PathBuf) -> Result<PathBuf> {
-    if path.is_relative() {
-        let cwd = std::env::current_dir().context("Failed to get current working directory")?;
-        if let Some(project_root) = cwd.parent() {
-            Ok(project_root.join(path))
-        } else {
-            Ok(path.clone())
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+// Lexically collapses `.` and `..` components without touching the filesystem
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+                _ => normalized.push(".."),
+            },
+            other => normalized.push(other.as_os_str()),
         }
-    } else {
-        Ok(path.clone())
+    }
+    normalized
+}
+pub fn resolve_file_path(path: &Path, project_root: Option<&Path>) -> Result<PathBuf> {
+    let Some(project_root) = project_root else {
+        if path.is_relative() {
+            return Err(anyhow!(
+                "Cannot resolve relative path without a project root: {}",
+                path.display()
+            ));
+        }
+        return Ok(normalize_path(path));
+    };
+    let root = normalize_path(project_root);
+    let resolved = normalize_path(&root.join(path));
+    if !resolved.starts_with(&root) {
+        return Err(anyhow!(
+            "Path {} resolves outside the project root {}",
+            path.display(),
+            root.display()
+        ));
+    }
+    Ok(resolved)
+}
+// Destination of a rename or copy, resolved against the same root as the source path
+pub fn resolve_target_path(
+    file_change: &FileChange,
+    project_root: Option<&Path>,
+) -> Result<Option<PathBuf>> {
+    file_change
+        .target
+        .as_ref()
+        .map(|target| resolve_file_path(target, project_root))
+        .transpose()
+}
+// Resolves every path in the plan up front so a single bad path rejects the whole plan.
+// Targets are validated here too; callers re-resolve them with resolve_target_path.
+pub fn resolve_plan_paths(
+    file_changes: &[FileChange],
+    project_root: Option<&Path>,
+) -> Result<Vec<PathBuf>> {
+    file_changes
+        .iter()
+        .map(|fc| {
+            resolve_target_path(fc, project_root)?;
+            resolve_file_path(&fc.path, project_root)
+        })
+        .collect()
+}
+// Target of a rename or copy. Whether something already exists there depends on the
+// caller's view of the filesystem (disk, transaction or preview overlay), so that is
+// checked there.
+pub fn target_path(
+    file_change: &FileChange,
+    resolved_path: &Path,
+    options: &ApplyOptions,
+) -> Result<PathBuf> {
+    let target =
+        resolve_target_path(file_change, options.project_root.as_deref())?.ok_or_else(|| {
+            anyhow!(
+                "Missing target path for {:?}: {}",
+                file_change.action,
+                resolved_path.display()
+            )
+        })?;
+    if target == resolved_path {
+        return Err(anyhow!(
+            "Target path is the same as the source: {}",
+            resolved_path.display()
+        ));
+    }
+    Ok(target)
+}
+fn line_of(content: &str, index: usize) -> usize {
+    content[..index].matches('\n').count() + 1
+}
+fn line_range(content: &str, range: &Range<usize>) -> LineRange {
+    // A trailing newline belongs to the last matched line, not the one after it
+    let last = range.end.saturating_sub(1).max(range.start);
+    LineRange {
+        start: line_of(content, range.start),
+        end: line_of(content, last),
+    }
+}
+// Carries the per-change results out of a failed modify so reports can still show
+// which of its changes matched
+#[derive(Debug)]
+pub struct ChangeFailure {
+    pub results: Vec<ChangeResult>,
+}
+impl fmt::Display for ChangeFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let failed: Vec<String> = self
+            .results
+            .iter()
+            .filter(|r| r.status == ChangeStatus::Failed)
+            .map(|r| {
+                let message = r.message.as_deref().unwrap_or("Unknown error");
+                if r.description.is_empty() {
+                    format!("Change {} failed: {}", r.id, message)
+                } else {
+                    format!("Change {} ({}) failed: {}", r.id, r.description, message)
+                }
+            })
+            .collect();
+        write!(f, "{}", failed.join("\n"))
     }
 }
-fn apply_change_to_content(content: &str, find_text: &str, replacement: &str) -> Result<String> {
+impl std::error::Error for ChangeFailure {}
+// Per-change results attached to an apply error, if it came from a modify
+pub fn change_results(err: &anyhow::Error) -> Vec<ChangeResult> {
+    err.downcast_ref::<ChangeFailure>()
+        .map(|failure| failure.results.clone())
+        .unwrap_or_default()
+}
+fn change_result(file_change: &FileChange, chg: &Change) -> ChangeResult {
+    ChangeResult {
+        id: change_id(file_change.index, chg.index),
+        file_index: file_change.index,
+        change_index: chg.index,
+        description: chg.description.trim().to_string(),
+        status: ChangeStatus::Applied,
+        matches: Vec::new(),
+        message: None,
+    }
+}
+// Enforces that a Search block is unique unless the change selects an occurrence or
+// carries a line hint, in which case the match starting nearest the hint wins.
+// Each candidate carries its byte range and the line it starts on.
+fn select_matches(
+    candidates: Vec<(Range<usize>, usize)>,
+    occurrence: Option<&Occurrence>,
+    line_hint: Option<usize>,
+) -> Result<Vec<Range<usize>>> {
+    let lines = candidates
+        .iter()
+        .map(|(_, line)| line.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    match occurrence {
+        None if candidates.len() > 1 && line_hint.is_some() => {
+            let hint = line_hint.unwrap_or_default();
+            let nearest = candidates
+                .into_iter()
+                .min_by_key(|(_, line)| line.abs_diff(hint))
+                .map(|(range, _)| range);
+            Ok(nearest.into_iter().collect())
+        }
+        None if candidates.len() > 1 => Err(anyhow!(
+            "Search block is ambiguous: {} matches at lines {}",
+            candidates.len(),
+            lines
+        )),
+        None => Ok(candidates.into_iter().map(|(range, _)| range).collect()),
+        Some(Occurrence::Nth(n)) => match candidates.into_iter().nth(n - 1) {
+            Some((range, _)) => Ok(vec![range]),
+            None => Err(anyhow!(
+                "Occurrence {} requested but Search block only matches at lines {}",
+                n,
+                lines
+            )),
+        },
+        Some(Occurrence::All) => Ok(candidates.into_iter().map(|(range, _)| range).collect()),
+    }
+}
+fn leading_whitespace(line: &str) -> &str {
+    &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+}
+fn first_code_line(text: &str) -> Option<&str> {
+    text.lines().find(|line| !line.trim().is_empty())
+}
+// For matches found despite whitespace differences: when a match starts at (or inside) a
+// line's indentation, widens it to the start of the line and shifts the replacement from
+// the Search snippet's indentation to the file's. Matches that start mid-line are spliced
+// verbatim.
+fn indent_replacement(
+    content: &str,
+    range: Range<usize>,
+    search: &str,
+    replacement: &str,
+) -> (Range<usize>, String) {
+    let line_start = content[..range.start].rfind('\n').map_or(0, |i| i + 1);
+    if !content[line_start..range.start].trim().is_empty() {
+        return (range, replacement.to_string());
+    }
+    let file_indent = first_code_line(&content[line_start..range.end])
+        .map(leading_whitespace)
+        .unwrap_or("");
+    let search_indent = first_code_line(search)
+        .map(leading_whitespace)
+        .unwrap_or("");
+    let lines: Vec<String> = replacement
+        .split('\n')
+        .map(|line| {
+            if line.trim().is_empty() {
+                return line.to_string();
+            }
+            match line.strip_prefix(search_indent) {
+                Some(rest) => format!("{}{}", file_indent, rest),
+                // Shallower than the snippet itself; nothing sensible to shift
+                None => line.to_string(),
+            }
+        })
+        .collect();
+    (line_start..range.end, lines.join("\n"))
+}
+// An exact match is spliced as written: Search and Content share the file's indentation,
+// and Content may deliberately dedent. Only inexact matches are re-indented.
+fn replace_ranges(
+    content: &str,
+    ranges: &[Range<usize>],
+    search: &str,
+    replacement: &str,
+    reindent: bool,
+) -> (String, Vec<LineRange>) {
+    let mut new_content = content.to_string();
+    // Back to front so earlier ranges stay valid
+    for range in ranges.iter().rev() {
+        let (range, text) = if reindent {
+            indent_replacement(content, range.clone(), search, replacement)
+        } else {
+            (range.clone(), replacement.to_string())
+        };
+        new_content.replace_range(range, &text);
+    }
+    let lines = ranges.iter().map(|r| line_range(content, r)).collect();
+    (new_content, lines)
+}
+fn apply_change_to_content(
+    content: &str,
+    find_text: &str,
+    replacement: &str,
+    occurrence: Option<&Occurrence>,
+    line_hint: Option<usize>,
+    fuzzy_threshold: f64,
+) -> Result<(String, Vec<LineRange>)> {
     debug!("apply_change_to_content - Start");
     debug!("apply_change_to_content - find_text:\n{}\n", find_text);
     debug!("apply_change_to_content - replacement:\n{}\n", replacement);
-    if let Some(start) = content.find(find_text) {
+    if find_text.trim().is_empty() {
+        return Err(anyhow!("Search block is empty"));
+    }
+    let exact: Vec<(Range<usize>, usize)> = content
+        .match_indices(find_text)
+        .map(|(start, _)| (start..start + find_text.len(), line_of(content, start)))
+        .collect();
+    if !exact.is_empty() {
         debug!(
-            "apply_change_to_content - Found exact substring at index: {}",
-            start
+            "apply_change_to_content - Found {} exact substring match(es)",
+            exact.len()
         );
-        let end = start + find_text.len();
-        let mut new_content = content.to_string();
-        new_content.replace_range(start..end, replacement);
+        let ranges = select_matches(exact, occurrence, line_hint)?;
         debug!("apply_change_to_content - Exact substring replacement succeeded");
-        return Ok(new_content);
+        return Ok(replace_ranges(
+            content,
+            &ranges,
+            find_text,
+            replacement,
+            false,
+        ));
     } else {
         debug!("apply_change_to_content - Exact substring not found, attempting fallback");
     }
-    let tokens: Vec<String> = find_text
-        .split_whitespace()
-        .map(|t| regex_escape(t))
-        .collect();
-    let pattern = format!("(?s)\\s*{}\\s*", tokens.join(r"\s+"));
+    let tokens: Vec<String> = find_text.split_whitespace().map(regex_escape).collect();
+    // No surrounding \s*: the whitespace around the match belongs to the file, not the change
+    let pattern = format!("(?s){}", tokens.join(r"\s+"));
     debug!(
         "apply_change_to_content - Fallback regex pattern: {}",
         pattern
     );
     let re = Regex::new(&pattern)?;
-    if let Some(mat) = re.find(content) {
+    let fallback: Vec<(Range<usize>, usize)> = re
+        .find_iter(content)
+        .map(|mat| (mat.range(), line_of(content, mat.start())))
+        .collect();
+    if !fallback.is_empty() {
         debug!(
-            "apply_change_to_content - Fallback regex match found at {}..{}",
-            mat.start(),
-            mat.end()
+            "apply_change_to_content - Found {} fallback regex match(es)",
+            fallback.len()
         );
-        let start = mat.start();
-        let end = mat.end();
-        let mut new_content = content.to_string();
-        new_content.replace_range(start..end, replacement);
+        let ranges = select_matches(fallback, occurrence, line_hint)?;
         debug!("apply_change_to_content - Fallback regex replacement succeeded");
-        return Ok(new_content);
+        return Ok(replace_ranges(
+            content,
+            &ranges,
+            find_text,
+            replacement,
+            true,
+        ));
+    }
+    debug!("apply_change_to_content - Search block not found via fallback regex, attempting fuzzy match");
+    let mut accepted: Vec<(Range<usize>, usize)> =
+        find_candidates(content, find_text, fuzzy_threshold)
+            .into_iter()
+            .map(|c| (c.range, c.start_line))
+            .collect();
+    if !accepted.is_empty() {
+        debug!(
+            "apply_change_to_content - Found {} fuzzy match(es) above {}",
+            accepted.len(),
+            fuzzy_threshold
+        );
+        accepted.sort_by_key(|(range, _)| range.start);
+        let ranges = select_matches(accepted, occurrence, line_hint)?;
+        return Ok(replace_ranges(
+            content,
+            &ranges,
+            find_text,
+            replacement,
+            true,
+        ));
+    }
+    match closest_candidate(content, find_text) {
+        Some(best) => Err(anyhow!(describe_closest(content, find_text, &best))),
+        None => Err(anyhow!("Search block not found")),
     }
-    debug!("apply_change_to_content - Search block not found via fallback regex");
-    Err(anyhow!("Search block not found"))
 }
-fn apply_modification_changes(original: &str, changes: &[Change]) -> Result<String> {
+// Pure insertion (a diff hunk without context or removed lines): `text` is placed so
+// that its first line becomes line `line` of the result.
+fn insert_at_line(content: &str, line: usize, text: &str) -> Result<(String, Vec<LineRange>)> {
+    let line_count = content.lines().count();
+    if line == 0 || line > line_count + 1 {
+        return Err(anyhow!(
+            "Cannot insert at line {}: file has {} lines",
+            line,
+            line_count
+        ));
+    }
+    let mut new_content = content.to_string();
+    let offset = if line == 1 {
+        0
+    } else {
+        match content.match_indices('\n').nth(line - 2) {
+            Some((index, _)) => index + 1,
+            None => {
+                // Appending after a last line that has no trailing newline
+                new_content.push('\n');
+                new_content.len()
+            }
+        }
+    };
+    new_content.insert_str(offset, text);
+    let inserted = text.lines().count().max(1);
+    let range = LineRange {
+        start: line,
+        end: line + inserted - 1,
+    };
+    Ok((new_content, vec![range]))
+}
+// Runs every change even after one fails, so the report says which ones would have
+// applied. Later changes see the output of the earlier ones that matched.
+fn apply_modification_changes(
+    original: &str,
+    file_change: &FileChange,
+    options: &ApplyOptions,
+) -> Result<(String, Vec<ChangeResult>)> {
+    let fuzzy_threshold = options.fuzzy_threshold.unwrap_or(DEFAULT_FUZZY_THRESHOLD);
+    check_excluded_overlaps(original, file_change, fuzzy_threshold)?;
     let mut content = original.to_owned();
-    for (i, chg) in changes.iter().enumerate() {
+    let mut results = Vec::new();
+    for (i, chg) in file_change.changes.iter().enumerate() {
         debug!("apply_modification_changes - Applying change #{}", i + 1);
-        let search_str = match &chg.search {
-            Some(s) => s,
-            None => return Err(anyhow!("Missing <search> block in modify action")),
+        let outcome = match (&chg.search, chg.line_hint) {
+            (Some(search_str), Some(line)) if search_str.is_empty() => {
+                insert_at_line(&content, line, &chg.content)
+            }
+            (Some(search_str), _) => apply_change_to_content(
+                &content,
+                search_str,
+                &chg.content,
+                chg.occurrence.as_ref(),
+                chg.line_hint,
+                fuzzy_threshold,
+            ),
+            (None, _) => Err(anyhow!("Missing <search> block in modify action")),
         };
-        content = apply_change_to_content(&content, search_str, &chg.content)?;
+        let mut result = change_result(file_change, chg);
+        match outcome {
+            Ok((new_content, matches)) => {
+                content = new_content;
+                result.matches = matches;
+            }
+            Err(err) => {
+                debug!(
+                    "apply_modification_changes - Change #{} failed: {}",
+                    i + 1,
+                    err
+                );
+                result.status = ChangeStatus::Failed;
+                result.message = Some(format!("{:#}", err));
+            }
+        }
+        results.push(result);
     }
-    Ok(content)
+    results.extend(file_change.excluded.iter().map(|chg| ChangeResult {
+        status: ChangeStatus::Excluded,
+        ..change_result(file_change, chg)
+    }));
+    results.sort_by_key(|result| result.change_index);
+    let any_failed = results.iter().any(|r| r.status == ChangeStatus::Failed);
+    let any_applied = results.iter().any(|r| r.status == ChangeStatus::Applied);
+    if !any_failed {
+        return Ok((content, results));
+    }
+    if options.skip_failed_changes && any_applied {
+        for result in results.iter_mut() {
+            if result.status == ChangeStatus::Failed {
+                result.status = ChangeStatus::Skipped;
+            }
+        }
+        return Ok((content, results));
+    }
+    for result in results.iter_mut() {
+        if result.status == ChangeStatus::Applied {
+            result.status = ChangeStatus::Matched;
+        }
+    }
+    Err(ChangeFailure { results }.into())
 }
-fn aggregate_changes(changes: &[Change]) -> String {
-    changes.iter().map(|chg| chg.content.clone()).collect()
+// Lines of the original file a change's Search block covers. Insertions cover none, and a
+// change that does not match covers nothing another change could collide with.
+fn original_lines(original: &str, chg: &Change, fuzzy_threshold: f64) -> Vec<LineRange> {
+    match &chg.search {
+        Some(search_str) if !search_str.is_empty() => apply_change_to_content(
+            original,
+            search_str,
+            &chg.content,
+            chg.occurrence.as_ref(),
+            chg.line_hint,
+            fuzzy_threshold,
+        )
+        .map(|(_, matches)| matches)
+        .unwrap_or_default(),
+        _ => Vec::new(),
+    }
 }
-pub fn apply_file_change(file_change: &FileChange) -> Result<()> {
-    let result = (|| -> Result<()> {
-        debug!("apply_file_change - Action: {:?}", file_change.action);
-        debug!("apply_file_change - Path: {:?}", file_change.path);
-        let resolved_path = resolve_file_path(&file_change.path)?;
-        debug!(
-            "apply_file_change - Resolved path: {}",
-            resolved_path.display()
-        );
-        match file_change.action {
-            Action::Modify => {
-                let original_contents = fs::read_to_string(&resolved_path)
-                    .context(format!("Could not read file: {}", resolved_path.display()))?;
-                debug!(
-                    "apply_file_change - Original file contents length: {}",
-                    original_contents.len()
-                );
-                let modified_contents =
-                    apply_modification_changes(&original_contents, &file_change.changes)?;
-                fs::write(&resolved_path, modified_contents)
-                    .context(format!("Could not write file: {}", resolved_path.display()))?;
-            }
-            Action::Rewrite => {
-                let final_contents = aggregate_changes(&file_change.changes);
-                if final_contents.trim().is_empty() {
+// A selective apply may leave changes of a file out, but not one that edits the same
+// lines as a change that applies: the selected change would be working from text the
+// plan expected to be different.
+fn check_excluded_overlaps(
+    original: &str,
+    file_change: &FileChange,
+    fuzzy_threshold: f64,
+) -> Result<()> {
+    if file_change.excluded.is_empty() {
+        return Ok(());
+    }
+    let selected: Vec<(&Change, Vec<LineRange>)> = file_change
+        .changes
+        .iter()
+        .map(|chg| (chg, original_lines(original, chg, fuzzy_threshold)))
+        .collect();
+    for skipped in &file_change.excluded {
+        for skipped_range in original_lines(original, skipped, fuzzy_threshold) {
+            for (chg, ranges) in &selected {
+                let overlaps = ranges.iter().any(|range| {
+                    range.start <= skipped_range.end && skipped_range.start <= range.end
+                });
+                if overlaps {
                     return Err(anyhow!(
-                        "Malformed plan protocol: rewritten file is empty. Change reverted."
+                        "Change {} overlaps change {}, which is not selected; select both or neither",
+                        change_id(file_change.index, chg.index),
+                        change_id(file_change.index, skipped.index)
                     ));
                 }
-                fs::write(&resolved_path, final_contents).context(format!(
-                    "Could not rewrite file: {}",
+            }
+        }
+    }
+    Ok(())
+}
+fn aggregate_changes(changes: &[Change]) -> String {
+    changes.iter().map(|chg| chg.content.clone()).collect()
+}
+// Decodes the file into LF-only text along with the format needed to write it back
+pub fn read_existing(path: &Path) -> Result<Option<(String, TextFormat)>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    if path.is_dir() {
+        return Err(anyhow!(
+            "Path is a directory, not a file: {}",
+            path.display()
+        ));
+    }
+    let bytes = fs::read(path).context(format!("Could not read file: {}", path.display()))?;
+    let decoded =
+        TextFormat::decode(&bytes).context(format!("Could not decode file: {}", path.display()))?;
+    Ok(Some(decoded))
+}
+// Applies the final-newline policy to what is about to be written. `existing` is the
+// file before the change, if there was one.
+fn finish_contents(
+    mut contents: String,
+    existing: Option<&str>,
+    path: &Path,
+    policy: FinalNewline,
+) -> String {
+    let insert = match policy {
+        FinalNewline::Insert => true,
+        FinalNewline::Remove => false,
+        FinalNewline::Preserve => return contents,
+        FinalNewline::Auto => insert_final_newline(path)
+            .or_else(|| existing.map(|existing| existing.ends_with('\n')))
+            .unwrap_or(true),
+    };
+    if insert {
+        // An empty file stays empty
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+    } else {
+        let kept = contents.trim_end_matches('\n').len();
+        contents.truncate(kept);
+    }
+    contents
+}
+// Computes what the file should look like after the change without touching disk.
+// `existing` is the current state of the file (None when it does not exist);
+// the returned contents are None when the file should be removed. For a rename they are
+// the contents of the file at its target path; the source path is always removed.
+pub fn compute_file_change(
+    file_change: &FileChange,
+    resolved_path: &Path,
+    existing: Option<&str>,
+    options: &ApplyOptions,
+) -> Result<(Option<String>, Vec<ChangeResult>)> {
+    // Whole-file actions have nothing to match; their changes apply together or not at all
+    let applied = || -> Vec<ChangeResult> {
+        file_change
+            .changes
+            .iter()
+            .map(|chg| change_result(file_change, chg))
+            .collect()
+    };
+    let computed = match file_change.action {
+        Action::Modify => {
+            let original_contents = existing
+                .ok_or_else(|| anyhow!("Could not read file: {}", resolved_path.display()))?;
+            debug!(
+                "compute_file_change - Original file contents length: {}",
+                original_contents.len()
+            );
+            let (modified_contents, results) =
+                apply_modification_changes(original_contents, file_change, options)?;
+            Ok((Some(modified_contents), results))
+        }
+        Action::Rewrite => {
+            let final_contents = aggregate_changes(&file_change.changes);
+            if final_contents.trim().is_empty() {
+                return Err(anyhow!(
+                    "Malformed plan protocol: rewritten file is empty. Change reverted."
+                ));
+            }
+            Ok((Some(final_contents), applied()))
+        }
+        Action::Create => {
+            if existing.is_some() {
+                return Err(anyhow!("File already exists: {}", resolved_path.display()));
+            }
+            Ok((Some(aggregate_changes(&file_change.changes)), applied()))
+        }
+        Action::Delete => {
+            if existing.is_none() {
+                return Err(anyhow!(
+                    "Cannot delete, file does not exist: {}",
                     resolved_path.display()
-                ))?;
+                ));
             }
-            Action::Create => {
-                if resolved_path.exists() {
-                    return Err(anyhow!("File already exists: {}", resolved_path.display()));
-                }
-                if let Some(parent) = resolved_path.parent() {
-                    fs::create_dir_all(parent).context(format!(
-                        "Could not create directories for: {}",
-                        resolved_path.display()
-                    ))?;
-                }
-                let new_contents = aggregate_changes(&file_change.changes);
-                fs::write(&resolved_path, new_contents).context(format!(
-                    "Could not create file: {}",
+            Ok((None, Vec::new()))
+        }
+        Action::Rename => {
+            let original_contents = existing.ok_or_else(|| {
+                anyhow!(
+                    "Cannot rename, file does not exist: {}",
+                    resolved_path.display()
+                )
+            })?;
+            if file_change.changes.is_empty() {
+                return Ok((Some(original_contents.to_string()), Vec::new()));
+            }
+            let (modified_contents, results) =
+                apply_modification_changes(original_contents, file_change, options)?;
+            Ok((Some(modified_contents), results))
+        }
+        Action::CreateDir | Action::DeleteDir | Action::Copy => Err(anyhow!(
+            "{:?} does not compute file contents: {}",
+            file_change.action,
+            resolved_path.display()
+        )),
+    };
+    let (contents, results) = computed?;
+    // An explicit policy in the options wins over what the plan implies
+    let policy = match (options.final_newline, file_change.final_newline) {
+        (FinalNewline::Auto, Some(policy)) => policy,
+        (policy, _) => policy,
+    };
+    let contents =
+        contents.map(|contents| finish_contents(contents, existing, resolved_path, policy));
+    Ok((contents, results))
+}
+
+// Tree actions work on the filesystem directly; text, formats and per-change results
+// do not apply to them.
+fn apply_tree_action(
+    file_change: &FileChange,
+    resolved_path: &Path,
+    options: &ApplyOptions,
+) -> Result<Vec<PathBuf>> {
+    match file_change.action {
+        Action::CreateDir => create_dir(resolved_path).map(|_| Vec::new()),
+        Action::DeleteDir => delete_dir(resolved_path),
+        Action::Copy => {
+            let target = target_path(file_change, resolved_path, options)?;
+            copy_path(resolved_path, &target)
+        }
+        // Without edits the bytes are moved untouched, whatever their encoding
+        Action::Rename if file_change.changes.is_empty() => {
+            let target = target_path(file_change, resolved_path, options)?;
+            let listing = move_path(resolved_path, &target)?;
+            if let Some(mode) = file_change.mode {
+                set_mode(&target, mode)?;
+            }
+            Ok(listing)
+        }
+        _ => Err(anyhow!(
+            "{:?} is not a tree action: {}",
+            file_change.action,
+            resolved_path.display()
+        )),
+    }
+}
+pub fn write_file_contents(
+    resolved_path: &Path,
+    contents: Option<&str>,
+    format: &TextFormat,
+    mode: Option<u32>,
+) -> Result<()> {
+    match contents {
+        Some(contents) => {
+            // An edited file keeps the line endings of the lines the edit left alone
+            let bytes = match fs::read(resolved_path) {
+                Ok(previous) => format.encode_edit(contents, &previous),
+                Err(_) => format.encode(contents),
+            }
+            .context(format!(
+                "Could not encode file: {}",
+                resolved_path.display()
+            ))?;
+            if let Some(parent) = resolved_path.parent() {
+                fs::create_dir_all(parent).context(format!(
+                    "Could not create directories for: {}",
                     resolved_path.display()
                 ))?;
             }
-            Action::Delete => {
-                if resolved_path.exists() {
-                    fs::remove_file(&resolved_path).context(format!(
-                        "Could not delete file: {}",
-                        resolved_path.display()
-                    ))?;
-                } else {
-                    return Err(anyhow!(
-                        "Cannot delete, file does not exist: {}",
-                        resolved_path.display()
-                    ));
-                }
+            atomic_write(resolved_path, &bytes, mode)
+                .context(format!("Could not write file: {}", resolved_path.display()))?;
+        }
+        None => {
+            fs::remove_file(resolved_path).context(format!(
+                "Could not delete file: {}",
+                resolved_path.display()
+            ))?;
+        }
+    }
+    Ok(())
+}
+pub fn apply_file_change(
+    file_change: &FileChange,
+    resolved_path: &Path,
+    options: &ApplyOptions,
+) -> Result<FileOutcome> {
+    let result = (|| -> Result<FileOutcome> {
+        debug!("apply_file_change - Action: {:?}", file_change.action);
+        debug!("apply_file_change - Path: {:?}", file_change.path);
+        debug!(
+            "apply_file_change - Resolved path: {}",
+            resolved_path.display()
+        );
+        if file_change.is_tree_change() {
+            let listing = apply_tree_action(file_change, resolved_path, options)?;
+            return Ok(FileOutcome {
+                changes: Vec::new(),
+                listing,
+            });
+        }
+        let existing = read_existing(resolved_path)?;
+        let format = existing
+            .as_ref()
+            .map_or(options.new_files, |(_, format)| *format);
+        let existing_text = existing.as_ref().map(|(text, _)| text.as_str());
+        let (new_contents, results) =
+            compute_file_change(file_change, resolved_path, existing_text, options)?;
+        if let Action::Rename = file_change.action {
+            let target = target_path(file_change, resolved_path, options)?;
+            if target.exists() {
+                return Err(anyhow!(
+                    "Refusing to overwrite existing path: {}",
+                    target.display()
+                ));
             }
+            let mode = file_change.mode.or_else(|| file_mode(resolved_path));
+            write_file_contents(&target, new_contents.as_deref(), &format, mode)?;
+            write_file_contents(resolved_path, None, &format, None)?;
+            debug!("apply_file_change - Renamed to {}", target.display());
+            return Ok(FileOutcome {
+                changes: results,
+                listing: Vec::new(),
+            });
         }
+        write_file_contents(
+            resolved_path,
+            new_contents.as_deref(),
+            &format,
+            file_change.mode,
+        )?;
         debug!("apply_file_change - Completed successfully");
-        Ok(())
+        Ok(FileOutcome {
+            changes: results,
+            listing: Vec::new(),
+        })
     })();
     if let Err(ref e) = result {
         sentry::capture_error(e.root_cause());
     }
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_plan::parse_plan;
+    use crate::test_support::scratch_dir;
+
+    fn apply_plan(root: &Path, plan: &str) -> Result<()> {
+        let options = ApplyOptions {
+            project_root: Some(root.to_path_buf()),
+            ..Default::default()
+        };
+        for file_change in parse_plan(plan)?.file_changes {
+            apply_file_change(&file_change, &root.join(&file_change.path), &options)?;
+        }
+        Ok(())
+    }
+
+    fn replace(content: &str, search: &str, replacement: &str) -> String {
+        apply_change_to_content(
+            content,
+            search,
+            replacement,
+            None,
+            None,
+            DEFAULT_FUZZY_THRESHOLD,
+        )
+        .unwrap()
+        .0
+    }
+
+    #[test]
+    fn exact_match_can_dedent() {
+        let content = "class A:\n    def helper(x):\n        return x\n";
+        let result = replace(
+            content,
+            "    def helper(x):\n        return x",
+            "def helper(x):\n    return x",
+        );
+        assert_eq!(result, "class A:\ndef helper(x):\n    return x\n");
+    }
+
+    #[test]
+    fn exact_match_is_spliced_verbatim() {
+        let content = "fn main() {\n    let a = 1;\n}\n";
+        let result = replace(content, "    let a = 1;", "    let a = 2;\n    let b = 3;");
+        assert_eq!(result, "fn main() {\n    let a = 2;\n    let b = 3;\n}\n");
+    }
+
+    #[test]
+    fn whitespace_tolerant_match_is_reindented() {
+        let content = "fn main() {\n        if x {\n            y();\n        }\n}\n";
+        let result = replace(
+            content,
+            "if x {\n    y();\n}",
+            "if x {\n    y();\n    z();\n}",
+        );
+        assert_eq!(
+            result,
+            "fn main() {\n        if x {\n            y();\n            z();\n        }\n}\n"
+        );
+    }
+
+    // Three candidates starting on lines 2, 10 and 30
+    fn candidates() -> Vec<(Range<usize>, usize)> {
+        vec![(0..3, 2), (10..13, 10), (40..43, 30)]
+    }
+
+    #[test]
+    fn ambiguous_matches_are_refused() {
+        let err = select_matches(candidates(), None, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Search block is ambiguous: 3 matches at lines 2, 10, 30"
+        );
+        let single = vec![(5..8, 4)];
+        assert_eq!(select_matches(single, None, None).unwrap(), vec![5..8]);
+    }
+
+    #[test]
+    fn occurrences_pick_matches() {
+        let nth = select_matches(candidates(), Some(&Occurrence::Nth(2)), None).unwrap();
+        assert_eq!(nth, vec![10..13]);
+        let all = select_matches(candidates(), Some(&Occurrence::All), None).unwrap();
+        assert_eq!(all, vec![0..3, 10..13, 40..43]);
+        let err = select_matches(candidates(), Some(&Occurrence::Nth(4)), None).unwrap_err();
+        assert!(err.to_string().contains("Occurrence 4"), "{}", err);
+        // An explicit occurrence wins over a line hint
+        let nth = select_matches(candidates(), Some(&Occurrence::Nth(1)), Some(30)).unwrap();
+        assert_eq!(nth, vec![0..3]);
+    }
+
+    #[test]
+    fn line_hints_pick_the_nearest_match() {
+        let nearest = select_matches(candidates(), None, Some(25)).unwrap();
+        assert_eq!(nearest, vec![40..43]);
+        let nearest = select_matches(candidates(), None, Some(7)).unwrap();
+        assert_eq!(nearest, vec![10..13]);
+    }
+
+    #[test]
+    fn occurrences_apply_to_content() {
+        let content = "x = 1\ny = 2\nx = 1\n";
+        let apply = |occurrence: Option<&Occurrence>, hint| {
+            apply_change_to_content(
+                content,
+                "x = 1",
+                "x = 3",
+                occurrence,
+                hint,
+                DEFAULT_FUZZY_THRESHOLD,
+            )
+            .map(|(text, _)| text)
+        };
+        assert!(apply(None, None).is_err());
+        assert_eq!(
+            apply(Some(&Occurrence::Nth(2)), None).unwrap(),
+            "x = 1\ny = 2\nx = 3\n"
+        );
+        assert_eq!(
+            apply(Some(&Occurrence::All), None).unwrap(),
+            "x = 3\ny = 2\nx = 3\n"
+        );
+        assert_eq!(apply(None, Some(1)).unwrap(), "x = 3\ny = 2\nx = 1\n");
+    }
+
+    #[test]
+    fn renames_move_the_bytes_untouched() {
+        let root = scratch_dir("rename-bytes");
+        let png = b"\x89PNG\r\n\x1a\n\0\0\x0aIHDR\xff\n".to_vec();
+        fs::write(root.join("logo.png"), &png).unwrap();
+        apply_plan(
+            &root,
+            "### File logo.png\n### Action rename\n### To img/logo.png\n",
+        )
+        .unwrap();
+        assert_eq!(fs::read(root.join("img/logo.png")).unwrap(), png);
+        assert!(!root.join("logo.png").exists());
+    }
+
+    #[test]
+    fn renames_directories_and_edits_renamed_files() {
+        let root = scratch_dir("rename-dir");
+        fs::create_dir(root.join("src")).unwrap();
+        fs::write(root.join("src/a.txt"), "one\n").unwrap();
+        let plan = "### File src\n### Action rename\n### To lib\n### File lib/a.txt\n### Action rename\n### To lib/b.txt\n#### Change\n**Search**:\n```\none\n```\n**Content**:\n```\n1\n```\n";
+        apply_plan(&root, plan).unwrap();
+        assert!(!root.join("src").exists());
+        assert!(!root.join("lib/a.txt").exists());
+        assert_eq!(fs::read_to_string(root.join("lib/b.txt")).unwrap(), "1\n");
+        // A directory cannot take edits
+        fs::create_dir(root.join("d")).unwrap();
+        let plan = "### File d\n### Action rename\n### To e\n#### Change\n**Search**:\n```\nx\n```\n**Content**:\n```\ny\n```\n";
+        assert!(apply_plan(&root, plan).is_err());
+        assert!(root.join("d").is_dir());
+    }
+
+    #[test]
+    fn final_newline_follows_the_policy() {
+        let root = scratch_dir("final-newline");
+        let path = root.join("a.txt");
+        let finish = |contents: &str, existing: Option<&str>, policy| {
+            finish_contents(contents.to_string(), existing, &path, policy)
+        };
+        assert_eq!(finish("a\n\n", None, FinalNewline::Remove), "a");
+        assert_eq!(finish("a", None, FinalNewline::Insert), "a\n");
+        assert_eq!(finish("", None, FinalNewline::Insert), "");
+        assert_eq!(finish("a", Some("b\n"), FinalNewline::Preserve), "a");
+        // Auto keeps the file's current ending and gives new files a newline
+        assert_eq!(finish("a", Some("b\n"), FinalNewline::Auto), "a\n");
+        assert_eq!(finish("a\n", Some("b"), FinalNewline::Auto), "a");
+        assert_eq!(finish("a", None, FinalNewline::Auto), "a\n");
+        // .editorconfig overrides the current ending, but not an explicit policy
+        fs::write(
+            root.join(".editorconfig"),
+            "root = true\n[*.txt]\ninsert_final_newline = false\n",
+        )
+        .unwrap();
+        assert_eq!(finish("a\n", Some("b\n"), FinalNewline::Auto), "a");
+        assert_eq!(finish("a", None, FinalNewline::Auto), "a");
+        assert_eq!(finish("a", None, FinalNewline::Insert), "a\n");
+    }
+}
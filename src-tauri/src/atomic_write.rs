@@ -0,0 +1,167 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// Sibling temp path so the final rename never crosses a filesystem boundary
+pub fn temp_path_for(path: &Path) -> Result<PathBuf> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid file path: {}", path.display()))?;
+    let unique = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let temp_name = format!(
+        ".{}.o11n-{}-{}.tmp",
+        name.to_string_lossy(),
+        std::process::id(),
+        unique
+    );
+    Ok(path.with_file_name(temp_name))
+}
+
+// Writes `bytes` to a temp file next to `path` and renames it into place, so a crash
+// leaves either the old or the new contents. Mode bits and (on Unix) ownership of the
+// file being replaced carry over; `mode` overrides the permission bits when given.
+// A symlink is written through: the file it points to gets the new contents.
+pub fn atomic_write(path: &Path, bytes: &[u8], mode: Option<u32>) -> Result<()> {
+    let path = &resolve_symlink(path)?;
+    let previous = fs::metadata(path).ok();
+    let temp_path = temp_path_for(path)?;
+    let result = (|| -> Result<()> {
+        let mut file: File = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)
+            .context(format!(
+                "Could not create temp file: {}",
+                temp_path.display()
+            ))?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        drop(file);
+
+        if let Some(previous) = &previous {
+            fs::set_permissions(&temp_path, previous.permissions())?;
+            copy_ownership(&temp_path, previous);
+        }
+        if let Some(mode) = mode {
+            set_mode(&temp_path, mode)?;
+        }
+        fs::rename(&temp_path, path).context(format!(
+            "Could not move temp file into place: {}",
+            path.display()
+        ))?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+// The file a symlink points to, so the rename replaces it rather than the link. Paths
+// that are not symlinks, including ones that do not exist yet, are returned as they are.
+fn resolve_symlink(path: &Path) -> Result<PathBuf> {
+    let is_link = fs::symlink_metadata(path)
+        .map(|meta| meta.file_type().is_symlink())
+        .unwrap_or(false);
+    if !is_link {
+        return Ok(path.to_path_buf());
+    }
+    fs::canonicalize(path).context(format!(
+        "Refusing to write through a dangling symlink: {}",
+        path.display()
+    ))
+}
+
+#[cfg(unix)]
+fn copy_ownership(path: &Path, previous: &fs::Metadata) {
+    use std::os::unix::fs::MetadataExt;
+    // Only succeeds when we own the file or run privileged; best effort otherwise
+    if let Err(e) = std::os::unix::fs::chown(path, Some(previous.uid()), Some(previous.gid())) {
+        log::debug!("Could not preserve ownership of {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(not(unix))]
+fn copy_ownership(_path: &Path, _previous: &fs::Metadata) {}
+
+// Permission bits of an existing file, so a moved file keeps them at its new path
+#[cfg(unix)]
+pub fn file_mode(path: &Path) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path)
+        .ok()
+        .map(|meta| meta.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+pub fn file_mode(_path: &Path) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+        .context(format!("Could not set permissions on: {}", path.display()))
+}
+
+#[cfg(not(unix))]
+pub fn set_mode(_path: &Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch_dir;
+
+    #[test]
+    fn replaces_contents_and_keeps_mode() {
+        let dir = scratch_dir("atomic-write-mode");
+        let path = dir.join("a.sh");
+        fs::write(&path, "old").unwrap();
+        set_mode(&path, 0o755).unwrap();
+        atomic_write(&path, b"new", None).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(file_mode(&path).map(|mode| mode & 0o777), Some(0o755));
+        let leftovers = fs::read_dir(&dir).unwrap().count();
+        assert_eq!(leftovers, 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_through_symlinks() {
+        let dir = scratch_dir("atomic-write-symlink");
+        fs::create_dir(dir.join("shared")).unwrap();
+        let target = dir.join("shared/config.txt");
+        fs::write(&target, "a=1\n").unwrap();
+        set_mode(&target, 0o600).unwrap();
+        let link = dir.join("config.txt");
+        std::os::unix::fs::symlink("shared/config.txt", &link).unwrap();
+
+        atomic_write(&link, b"a=2\n", None).unwrap();
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(fs::read_to_string(&target).unwrap(), "a=2\n");
+        assert_eq!(file_mode(&target).map(|mode| mode & 0o777), Some(0o600));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_dangling_symlinks() {
+        let dir = scratch_dir("atomic-write-dangling");
+        let link = dir.join("config.txt");
+        std::os::unix::fs::symlink("missing.txt", &link).unwrap();
+        assert!(atomic_write(&link, b"x", None).is_err());
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+    }
+}
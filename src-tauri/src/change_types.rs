@@ -1,34 +1,414 @@
-use serde::Serialize;
+use crate::parse_plan::PlanFormat;
+use crate::text_encoding::TextFormat;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 
-#[derive(Debug, Clone)]
+// Which match of a Search block a modify change targets when it occurs more than once
+#[derive(Debug, Clone, PartialEq)]
+pub enum Occurrence {
+    Nth(usize),
+    All,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Change {
+    // Position within its FileChange; the second half of the change id
+    pub index: usize,
     pub description: String,
     pub search: Option<String>,
     pub content: String,
+    pub occurrence: Option<Occurrence>,
+    // 1-based line where the change is expected to land (from a diff hunk header); picks
+    // the nearest match when Search is ambiguous and places insertions with no Search
+    pub line_hint: Option<usize>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum Action {
     Modify,
     Rewrite,
     Create,
     Delete,
+    // Moves the file to `FileChange::target`; any changes are applied to the moved file
+    Rename,
+    CreateDir,
+    // Recursive; the result lists everything that was removed
+    DeleteDir,
+    // Copies a file or directory to `FileChange::target`
+    Copy,
 }
 
-#[derive(Debug, Clone)]
+impl Action {
+    // Actions that work on the filesystem tree rather than on a file's decoded text
+    pub fn is_tree_action(&self) -> bool {
+        matches!(self, Action::CreateDir | Action::DeleteDir | Action::Copy)
+    }
+
+    // The name used in plans, e.g. "create-dir"
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Modify => "modify",
+            Action::Rewrite => "rewrite",
+            Action::Create => "create",
+            Action::Delete => "delete",
+            Action::Rename => "rename",
+            Action::CreateDir => "create-dir",
+            Action::DeleteDir => "delete-dir",
+            Action::Copy => "copy",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct FileChange {
+    // Position in the plan; the first half of every change id in reports
+    pub index: usize,
     pub path: PathBuf,
     pub action: Action,
     pub changes: Vec<Change>,
+    // Unix permission bits requested with `### Mode` (e.g. executable scripts)
+    pub mode: Option<u32>,
+    // Short content hash of the file the model was shown, from `### Hash`
+    pub hash: Option<String>,
+    // Destination of a rename or copy, from `### To`
+    pub target: Option<PathBuf>,
+    // Set by dialects that spell out how the file ends (diffs); otherwise the options decide
+    pub final_newline: Option<FinalNewline>,
+    // Changes the user left out of a selective apply; only checked for overlaps with the
+    // ones that apply
+    pub excluded: Vec<Change>,
+}
+impl FileChange {
+    pub fn id(&self) -> String {
+        self.index.to_string()
+    }
+
+    // Tree actions, plus renames without edits: those move the file or directory as it
+    // is rather than rewriting its decoded text
+    pub fn is_tree_change(&self) -> bool {
+        self.action.is_tree_action() || (self.action == Action::Rename && self.changes.is_empty())
+    }
+}
+// "<file index>:<change index>", both 0-based positions in the plan as parsed
+pub fn change_id(file_index: usize, change_index: usize) -> String {
+    format!("{}:{}", file_index, change_index)
+}
+// A parsed plan: the file changes and the prose the model wrote around them
+#[derive(Debug, Clone)]
+pub struct Plan {
+    pub format: PlanFormat,
+    // Text before the first file change, such as the `# Plan` section or a commit message
+    pub narrative: String,
+    pub file_changes: Vec<FileChange>,
+    // What the lenient markdown parser corrected or ignored
+    pub warnings: Vec<Diagnostic>,
+}
+impl Plan {
+    pub fn summary(&self) -> PlanSummary {
+        let mut actions = BTreeMap::new();
+        for file in &self.file_changes {
+            *actions.entry(file.action.name()).or_insert(0) += 1;
+        }
+        PlanSummary {
+            format: self.format,
+            narrative: self.narrative.clone(),
+            files: self
+                .file_changes
+                .iter()
+                .map(|file| FileSummary {
+                    id: file.id(),
+                    index: file.index,
+                    path: file.path.clone(),
+                    action: file.action.clone(),
+                    target: file.target.clone(),
+                    descriptions: file
+                        .changes
+                        .iter()
+                        .map(|change| change.description.clone())
+                        .filter(|description| !description.is_empty())
+                        .collect(),
+                    changes: file.changes.len(),
+                    change_ids: file
+                        .changes
+                        .iter()
+                        .map(|change| change_id(file.index, change.index))
+                        .collect(),
+                })
+                .collect(),
+            actions,
+            changes: self.file_changes.iter().map(|f| f.changes.len()).sum(),
+            warnings: self.warnings.clone(),
+        }
+    }
+}
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileSummary {
+    pub id: String,
+    pub index: usize,
+    pub path: PathBuf,
+    pub action: Action,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,
+    pub descriptions: Vec<String>,
+    pub changes: usize,
+    pub change_ids: Vec<String>,
+}
+// What a plan does and why, for the preview, history and commit message
+#[derive(Debug, Clone, Serialize)]
+pub struct PlanSummary {
+    pub format: PlanFormat,
+    pub narrative: String,
+    pub files: Vec<FileSummary>,
+    // Number of files per action, keyed by the action's name
+    pub actions: BTreeMap<&'static str, usize>,
+    // Change blocks across all files
+    pub changes: usize,
+    pub warnings: Vec<Diagnostic>,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeStatus {
+    // Written to disk
+    Applied,
+    // Would have applied, but the file was not written because another change failed
+    Matched,
+    Failed,
+    // Failed and left out because `skipFailedChanges` was set; the rest of the file applied
+    Skipped,
+    // Not in the `include` list of a selective apply
+    Excluded,
+}
+// 1-based, inclusive, in the file as it was when the change ran
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct LineRange {
+    pub start: usize,
+    pub end: usize,
+}
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangeResult {
+    // "<file index>:<change index>", both 0-based positions in the plan
+    pub id: String,
+    pub file_index: usize,
+    pub change_index: usize,
+    pub description: String,
+    pub status: ChangeStatus,
+    pub matches: Vec<LineRange>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
 }
 #[derive(Debug, Clone, Serialize)]
 pub struct FileError {
     pub path: PathBuf,
     pub messages: Vec<String>,
+    pub changes: Vec<ChangeResult>,
 }
 #[derive(Debug, Clone, Serialize)]
 pub struct FileSuccess {
+    // Index of the FileChange it reports on; a plan can touch the same path twice
+    #[serde(skip)]
+    pub index: usize,
     pub path: PathBuf,
     pub messages: Vec<String>,
+    pub changes: Vec<ChangeResult>,
+    // Entries removed by delete-dir or copied by copy, relative to the directory
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub listing: Vec<PathBuf>,
+}
+// What applying (or staging) one FileChange produced
+#[derive(Debug, Clone, Default)]
+pub struct FileOutcome {
+    pub changes: Vec<ChangeResult>,
+    pub listing: Vec<PathBuf>,
+}
+// How a written file ends. Auto follows .editorconfig's insert_final_newline, then the
+// file's current ending; new files get a final newline.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FinalNewline {
+    #[default]
+    Auto,
+    Insert,
+    Remove,
+    // Write exactly what the change produced
+    Preserve,
+}
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ApplyOptions {
+    // All-or-nothing: stage every change first and roll back on any failure
+    pub transactional: bool,
+    // Folder open in the FileExplorer; plan paths resolve against it and may not escape it
+    pub project_root: Option<PathBuf>,
+    // Protected paths the user explicitly agreed to let this plan write
+    pub confirmed_paths: Vec<PathBuf>,
+    // Minimum similarity (0.0-1.0) for the fuzzy Search matcher; defaults to 0.9
+    pub fuzzy_threshold: Option<f64>,
+    // Project policy for files that do not exist yet; existing files keep their own format
+    pub new_files: TextFormat,
+    // Skip files whose `### Hash` no longer matches the disk instead of only warning
+    pub refuse_stale: bool,
+    // Apply the changes of a modify that matched and skip the ones that did not,
+    // instead of failing the whole file
+    pub skip_failed_changes: bool,
+    pub final_newline: FinalNewline,
+    // Ids of the file changes ("3") and changes ("3:1") to apply; the rest of the plan is
+    // left out. None applies everything.
+    pub include: Option<Vec<String>>,
+    // Which candidate plan in the pasted text to use (see extract_plans); required when
+    // the text holds more than one
+    pub plan_index: Option<usize>,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    // Something the parser corrected or ignored; the plan still applies
+    Warning,
+}
+// A problem in the pasted plan, located so the editor can underline it. Lines and
+// columns are 1-based and `end_column` is exclusive.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub end_column: usize,
+    pub severity: Severity,
+    // Stable identifier such as "missing-action" for the UI to key off
+    pub code: &'static str,
+    pub message: String,
+}
+impl Diagnostic {
+    pub fn new(
+        severity: Severity,
+        code: &'static str,
+        line: usize,
+        (column, end_column): (usize, usize),
+        message: String,
+    ) -> Diagnostic {
+        Diagnostic {
+            line,
+            column,
+            end_column,
+            severity,
+            code,
+            message,
+        }
+    }
+}
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockedFile {
+    // Id of the held-back FileChange, for `include` when it is applied after all
+    pub id: String,
+    pub path: PathBuf,
+    pub reason: String,
+    // What to send in `confirmedPaths` to let it through: its path and any target
+    pub confirm: Vec<PathBuf>,
+}
+#[derive(Debug, Clone, Serialize)]
+pub struct StaleFile {
+    pub path: PathBuf,
+    pub expected: String,
+    pub actual: Option<String>,
+}
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ApplyReport {
+    pub success: Vec<FileSuccess>,
+    pub errors: Vec<FileError>,
+    // Set when a transactional apply was aborted; names the file that caused it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub aborted: Option<FileError>,
+    // Journal entry that can undo this apply; None when nothing was written
+    #[serde(rename = "journalId")]
+    pub journal_id: Option<String>,
+    // Protected files that were skipped; resend their `confirm` paths in `confirmedPaths`
+    // to apply them
+    pub blocked: Vec<BlockedFile>,
+    // Files edited since the prompt was generated; skipped when `refuseStale` is set
+    pub stale: Vec<StaleFile>,
+    pub warnings: Vec<Diagnostic>,
+}
+#[derive(Debug, Clone, Serialize)]
+pub struct FilePreview {
+    pub path: PathBuf,
+    pub action: Action,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,
+    // None when the file does not exist before (or after) the change
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub diff: String,
+    pub added: usize,
+    pub removed: usize,
+    pub errors: Vec<String>,
+    pub changes: Vec<ChangeResult>,
+    // What delete-dir would remove or copy would copy, relative to the directory
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub listing: Vec<PathBuf>,
+}
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PreviewReport {
+    pub files: Vec<FilePreview>,
+    pub warnings: Vec<Diagnostic>,
+}
+#[derive(Debug, Clone, Serialize)]
+pub struct ValidationReport {
+    pub format: PlanFormat,
+    pub valid: bool,
+    // Number of file changes the plan would make; 0 when it is invalid
+    pub files: usize,
+    pub diagnostics: Vec<Diagnostic>,
+}
+// One edit to a parsed plan, addressed by the same 0-based positions as change ids
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+pub enum PlanEdit {
+    #[serde(rename_all = "camelCase")]
+    DeleteFile { file_index: usize },
+    #[serde(rename_all = "camelCase")]
+    DeleteChange {
+        file_index: usize,
+        change_index: usize,
+    },
+    // Fields left out keep their current value
+    #[serde(rename_all = "camelCase")]
+    EditChange {
+        file_index: usize,
+        change_index: usize,
+        description: Option<String>,
+        search: Option<String>,
+        content: Option<String>,
+    },
+}
+#[derive(Debug, Clone, Serialize)]
+pub struct EditedPlan {
+    pub format: PlanFormat,
+    pub plan: String,
+    pub files: usize,
+}
+// A plan found in pasted text such as a whole chat reply
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CandidatePlan {
+    pub index: usize,
+    // Byte offsets into the pasted text, end-exclusive
+    pub start: usize,
+    pub end: usize,
+    // 1-based and inclusive
+    pub start_line: usize,
+    pub end_line: usize,
+    pub text: String,
+    pub format: PlanFormat,
+    pub valid: bool,
+    pub files: usize,
+    pub diagnostics: Vec<Diagnostic>,
+}
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UndoReport {
+    pub restored: Vec<PathBuf>,
+    pub conflicts: Vec<FileError>,
 }
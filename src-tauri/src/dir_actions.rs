@@ -0,0 +1,149 @@
+use anyhow::{anyhow, Context, Result};
+use log::debug;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Every file, symlink and subdirectory under `dir`, relative to it, parents before
+// children. Symlinks are listed but never followed.
+pub fn list_tree(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut listing = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        let entries = fs::read_dir(dir.join(&relative)).context(format!(
+            "Could not list directory: {}",
+            dir.join(&relative).display()
+        ))?;
+        for dent in entries {
+            let dent = dent?;
+            let child = relative.join(dent.file_name());
+            if dent.file_type()?.is_dir() {
+                pending.push(child.clone());
+            }
+            listing.push(child);
+        }
+    }
+    listing.sort();
+    Ok(listing)
+}
+
+pub fn create_dir(path: &Path) -> Result<()> {
+    if fs::symlink_metadata(path).is_ok() {
+        return Err(anyhow!("Path already exists: {}", path.display()));
+    }
+    fs::create_dir_all(path).context(format!("Could not create directory: {}", path.display()))
+}
+
+// Removes the directory and everything below it, returning what was removed
+pub fn delete_dir(path: &Path) -> Result<Vec<PathBuf>> {
+    if !fs::symlink_metadata(path).is_ok_and(|meta| meta.is_dir()) {
+        return Err(anyhow!(
+            "Cannot delete, directory does not exist: {}",
+            path.display()
+        ));
+    }
+    let listing = list_tree(path)?;
+    fs::remove_dir_all(path).context(format!("Could not delete directory: {}", path.display()))?;
+    debug!(
+        "delete_dir - Removed {} ({} entries)",
+        path.display(),
+        listing.len()
+    );
+    Ok(listing)
+}
+
+// Copies a file or a whole directory to `to`, which must not exist yet. Permissions
+// come along; symlinks are recreated rather than followed so a copy can never pull in
+// anything from outside the tree. Returns what was copied, relative to `from`.
+pub fn copy_path(from: &Path, to: &Path) -> Result<Vec<PathBuf>> {
+    let meta = fs::symlink_metadata(from).context(format!(
+        "Cannot copy, path does not exist: {}",
+        from.display()
+    ))?;
+    if fs::symlink_metadata(to).is_ok() {
+        return Err(anyhow!(
+            "Refusing to overwrite existing path: {}",
+            to.display()
+        ));
+    }
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent).context(format!(
+            "Could not create directories for: {}",
+            to.display()
+        ))?;
+    }
+    if !meta.is_dir() {
+        copy_entry(from, to)?;
+        return Ok(Vec::new());
+    }
+    let listing = list_tree(from)?;
+    fs::create_dir(to).context(format!("Could not create directory: {}", to.display()))?;
+    fs::set_permissions(to, meta.permissions())?;
+    for relative in &listing {
+        copy_entry(&from.join(relative), &to.join(relative))?;
+    }
+    Ok(listing)
+}
+
+// Moves a file or a whole directory to `to`, which must not exist yet, creating missing
+// parent directories. Returns what was moved, relative to `from`.
+pub fn move_path(from: &Path, to: &Path) -> Result<Vec<PathBuf>> {
+    let meta = fs::symlink_metadata(from).context(format!(
+        "Cannot rename, path does not exist: {}",
+        from.display()
+    ))?;
+    if fs::symlink_metadata(to).is_ok() {
+        return Err(anyhow!(
+            "Refusing to overwrite existing path: {}",
+            to.display()
+        ));
+    }
+    let listing = if meta.is_dir() {
+        list_tree(from)?
+    } else {
+        Vec::new()
+    };
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent).context(format!(
+            "Could not create directories for: {}",
+            to.display()
+        ))?;
+    }
+    fs::rename(from, to).context(format!(
+        "Could not rename {} to {}",
+        from.display(),
+        to.display()
+    ))?;
+    Ok(listing)
+}
+
+fn copy_entry(from: &Path, to: &Path) -> Result<()> {
+    let meta = fs::symlink_metadata(from)?;
+    if meta.is_dir() {
+        fs::create_dir(to)?;
+        fs::set_permissions(to, meta.permissions())?;
+    } else if meta.file_type().is_symlink() {
+        copy_symlink(from, to)?;
+    } else {
+        fs::copy(from, to).context(format!(
+            "Could not copy {} to {}",
+            from.display(),
+            to.display()
+        ))?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_symlink(from: &Path, to: &Path) -> Result<()> {
+    let link = fs::read_link(from)?;
+    std::os::unix::fs::symlink(link, to)
+        .context(format!("Could not copy symlink: {}", from.display()))
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(from: &Path, _to: &Path) -> Result<()> {
+    Err(anyhow!(
+        "Copying symlinks is not supported on this platform: {}",
+        from.display()
+    ))
+}
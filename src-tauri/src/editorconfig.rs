@@ -0,0 +1,106 @@
+use ignore::gitignore::GitignoreBuilder;
+use std::fs;
+use std::path::Path;
+
+const EDITORCONFIG: &str = ".editorconfig";
+
+// `insert_final_newline` for `path` from the .editorconfig files above it. The nearest
+// file that sets it wins, and within a file the last matching section; the search stops
+// at a file marked `root = true`. None when nothing sets it (or it is "unset").
+pub fn insert_final_newline(path: &Path) -> Option<bool> {
+    let mut dir = path.parent();
+    while let Some(current) = dir {
+        if let Ok(text) = fs::read_to_string(current.join(EDITORCONFIG)) {
+            let (value, root) = lookup(&text, current, path, "insert_final_newline");
+            if let Some(value) = value {
+                return match value.as_str() {
+                    "true" => Some(true),
+                    "false" => Some(false),
+                    _ => None,
+                };
+            }
+            if root {
+                break;
+            }
+        }
+        dir = current.parent();
+    }
+    None
+}
+
+// The value of `key` for `path` in one .editorconfig, and whether the file is a root
+fn lookup(text: &str, dir: &Path, path: &Path, key: &str) -> (Option<String>, bool) {
+    let mut root = false;
+    let mut value = None;
+    // None before the first section (the preamble), then whether the section matches
+    let mut section_matches: Option<bool> = None;
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(pattern) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section_matches = Some(section_matches_path(pattern, dir, path));
+            continue;
+        }
+        let Some((name, setting)) = line.split_once('=') else {
+            continue;
+        };
+        let name = name.trim().to_lowercase();
+        let setting = setting.trim().to_lowercase();
+        match section_matches {
+            None if name == "root" => root = setting == "true",
+            Some(true) if name == key => value = Some(setting),
+            _ => {}
+        }
+    }
+    (value, root)
+}
+
+// Section globs behave like gitignore patterns relative to the .editorconfig: without a
+// slash they match a file name at any depth, with one they are anchored to the directory
+fn section_matches_path(pattern: &str, dir: &Path, path: &Path) -> bool {
+    let mut builder = GitignoreBuilder::new(dir);
+    if builder.add_line(None, pattern).is_err() {
+        return false;
+    }
+    match builder.build() {
+        Ok(matcher) => matcher.matched(path, false).is_ignore(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch_dir;
+
+    #[test]
+    fn nearest_setting_wins_until_root() {
+        let root = scratch_dir("editorconfig-lookup");
+        fs::create_dir_all(root.join("app/src")).unwrap();
+        fs::write(
+            root.join(EDITORCONFIG),
+            "root = true\n[*]\ninsert_final_newline = true\n[*.md]\ninsert_final_newline = false\n",
+        )
+        .unwrap();
+        fs::write(
+            root.join("app").join(EDITORCONFIG),
+            "[src/*.rs]\ninsert_final_newline = false\n[*.txt]\ninsert_final_newline = unset\n",
+        )
+        .unwrap();
+        // The last matching section of a file wins
+        assert_eq!(insert_final_newline(&root.join("notes.md")), Some(false));
+        assert_eq!(insert_final_newline(&root.join("main.rs")), Some(true));
+        // Anchored patterns match relative to their .editorconfig
+        assert_eq!(
+            insert_final_newline(&root.join("app/src/lib.rs")),
+            Some(false)
+        );
+        assert_eq!(insert_final_newline(&root.join("app/lib.rs")), Some(true));
+        assert_eq!(insert_final_newline(&root.join("app/a.txt")), None);
+        // Files above a root are not read
+        fs::write(root.join("app").join(EDITORCONFIG), "root = true\n").unwrap();
+        assert_eq!(insert_final_newline(&root.join("app/lib.rs")), None);
+    }
+}
@@ -0,0 +1,372 @@
+use crate::change_types::CandidatePlan;
+use crate::parse_change_protocol::{is_code_field, is_file_header, Fence};
+use crate::parse_plan::{detect_format, parse_plan, validate_plan, PlanFormat};
+use crate::parse_search_replace::is_search_marker;
+use crate::parse_xml_protocol::is_file_tag;
+use anyhow::{anyhow, Result};
+
+// A run of lines that may hold a plan, as indices into the lines being scanned
+struct Region {
+    start: usize,
+    end: usize,
+    search_replace: bool,
+}
+
+// Finds every plan in arbitrary text: each <pre> block, fenced diffs, XML and
+// SEARCH/REPLACE blocks, and markdown plans written straight into the reply. Text that
+// only contains prose and ordinary code yields no candidates.
+pub fn extract_plans(input: &str) -> Vec<CandidatePlan> {
+    let mut candidates = Vec::new();
+    for (start, end) in plan_spans(input) {
+        let (start, end) = trim_span(input, start, end);
+        let text = &input[start..end];
+        if text.is_empty() {
+            continue;
+        }
+        let start_line = input[..start].matches('\n').count() + 1;
+        // Validated with the lines above it blanked out, so diagnostics point into the
+        // pasted text rather than into the candidate
+        let report = validate_plan(&pad_lines(text, start_line));
+        let looks_like_plan = report.format != PlanFormat::Markdown
+            || text.lines().any(|line| is_file_header(line.trim_end()));
+        if report.files == 0 && (report.valid || !looks_like_plan) {
+            continue;
+        }
+        candidates.push(CandidatePlan {
+            index: candidates.len(),
+            start,
+            end,
+            start_line,
+            end_line: start_line + text.matches('\n').count(),
+            text: text.to_string(),
+            format: report.format,
+            valid: report.valid,
+            files: report.files,
+            diagnostics: report.diagnostics,
+        });
+    }
+    candidates
+}
+
+// Byte ranges that may hold a plan: the body of each <pre> block and the candidates in
+// the text between them. Only a <pre> or </pre> on its own line and outside a code fence
+// delimits a block, so plans whose code contains the tags are read as written.
+fn plan_spans(input: &str) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut gap_start = 0;
+    let mut body_start: Option<usize> = None;
+    let mut open_fence: Option<Fence> = None;
+    let mut offset = 0;
+    for line in input.split_inclusive('\n') {
+        let (line_start, line_end) = (offset, offset + line.len());
+        offset = line_end;
+        if let Some(fence) = &open_fence {
+            if fence.closes(line) {
+                open_fence = None;
+            }
+            continue;
+        }
+        let tag = line.trim();
+        if body_start.is_none() && tag.eq_ignore_ascii_case("<pre>") {
+            spans.extend(gap_spans(input, gap_start, line_start));
+            body_start = Some(line_end);
+        } else if let (Some(start), true) = (body_start, tag.eq_ignore_ascii_case("</pre>")) {
+            spans.push((start, line_start));
+            gap_start = line_end;
+            body_start = None;
+        } else if let Some(fence) = Fence::open(line) {
+            open_fence = Some(fence);
+        }
+    }
+    let Some(start) = body_start else {
+        spans.extend(gap_spans(input, gap_start, input.len()));
+        return spans;
+    };
+    // A fence left open inside the block (such as a wrapper the plan's own fences closed
+    // early) hides the closing tag; fall back to the last one on its own line
+    let mut offset = start;
+    let close = input[start..]
+        .split_inclusive('\n')
+        .map(|line| {
+            offset += line.len();
+            (offset - line.len(), offset, line)
+        })
+        .filter(|(_, _, line)| line.trim().eq_ignore_ascii_case("</pre>"))
+        .last();
+    match close {
+        Some((line_start, line_end, _)) => {
+            spans.push((start, line_start));
+            spans.extend(gap_spans(input, line_end, input.len()));
+        }
+        None => spans.push((start, input.len())),
+    }
+    spans
+}
+
+// The plan apply and preview should run: the chosen candidate, or the only one. Several
+// candidates without a choice are refused rather than mixed together; text without any
+// is passed through so the parser reports what is wrong with it.
+pub fn select_plan(input: &str, plan_index: Option<usize>) -> Result<String> {
+    let candidates = extract_plans(input);
+    let count = candidates.len();
+    let candidate = match plan_index {
+        Some(index) => candidates.into_iter().nth(index).ok_or_else(|| {
+            anyhow!(
+                "Plan {} was requested but the text contains {} plans",
+                index,
+                count
+            )
+        })?,
+        None if count > 1 => {
+            return Err(anyhow!(
+                "The text contains {} candidate plans; choose one with planIndex",
+                count
+            ))
+        }
+        None => match candidates.into_iter().next() {
+            Some(candidate) => candidate,
+            None => return Ok(input.to_string()),
+        },
+    };
+    Ok(pad_lines(&candidate.text, candidate.start_line))
+}
+
+// Keeps line numbers in warnings and errors the same as in the pasted text
+fn pad_lines(text: &str, start_line: usize) -> String {
+    format!("{}{}", "\n".repeat(start_line - 1), text)
+}
+
+fn trim_span(input: &str, start: usize, end: usize) -> (usize, usize) {
+    let text = &input[start..end];
+    let leading = text.len() - text.trim_start().len();
+    (start + leading, start + text.trim_end().len().max(leading))
+}
+
+fn is_plan_heading(line: &str) -> bool {
+    line.trim().eq_ignore_ascii_case("# Plan")
+}
+
+// A plan in a dialect other than markdown that is not inside a fence
+fn starts_other_dialect(lines: &[&str], i: usize) -> bool {
+    let line = lines[i];
+    is_file_tag(line)
+        || is_search_marker(line)
+        || line.starts_with("diff --git ")
+        || (line.starts_with("--- ") && lines.get(i + 1).is_some_and(|l| l.starts_with("+++ ")))
+}
+
+// Candidate spans between two <pre> blocks (or outside any)
+fn gap_spans(input: &str, from: usize, to: usize) -> Vec<(usize, usize)> {
+    let mut offsets = Vec::new();
+    let mut lines = Vec::new();
+    let mut offset = from;
+    for line in input[from..to].split_inclusive('\n') {
+        offsets.push(offset);
+        lines.push(line.trim_end());
+        offset += line.len();
+    }
+    offsets.push(to);
+
+    let mut regions: Vec<Region> = Vec::new();
+    // First line not yet part of a region
+    let mut rest = 0;
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        if is_plan_heading(line) || is_file_header(line) {
+            if let Some(end) = markdown_end(&lines, i, None) {
+                regions.push(Region {
+                    start: i,
+                    end,
+                    search_replace: false,
+                });
+                rest = end;
+                i = end;
+                continue;
+            }
+        } else if let Some(fence) = Fence::open(line) {
+            let closes: Vec<usize> = (i + 1..lines.len())
+                .filter(|&j| fence.closes(lines[j]))
+                .collect();
+            let Some(&first_close) = closes.first() else {
+                i += 1;
+                continue;
+            };
+            let mut format = detect_format(&lines[i + 1..first_close].join("\n"));
+            // A fence wrapped around a markdown plan is closed early by the first fence
+            // inside it, so the plan is read from here as markdown instead
+            if format == PlanFormat::Markdown
+                && lines[i + 1..first_close].iter().any(|l| is_file_header(l))
+            {
+                if let Some(end) = markdown_end(&lines, i + 1, Some(&fence)) {
+                    regions.push(Region {
+                        start: i + 1,
+                        end,
+                        search_replace: false,
+                    });
+                    // Past the wrapper's closing fence, which is not part of the plan
+                    let after = (end..lines.len())
+                        .find(|&j| !lines[j].trim().is_empty())
+                        .filter(|&j| fence.closes(lines[j]))
+                        .map_or(end, |j| j + 1);
+                    rest = after;
+                    i = after;
+                    continue;
+                }
+            }
+            // The same goes for XML and diffs whose code contains fences: the block ends at
+            // the first closing fence where the plan parses
+            let close = match format {
+                PlanFormat::Markdown | PlanFormat::SearchReplace => first_close,
+                _ => closes
+                    .iter()
+                    .copied()
+                    .find(|&j| parse_plan(&lines[i + 1..j].join("\n")).is_ok())
+                    .unwrap_or(first_close),
+            };
+            if close != first_close {
+                format = detect_format(&lines[i + 1..close].join("\n"));
+            }
+            if format == PlanFormat::SearchReplace {
+                // The blocks name their file on the line above the fence
+                let start = if i > rest && !lines[i - 1].trim().is_empty() {
+                    i - 1
+                } else {
+                    i
+                };
+                match regions.last_mut() {
+                    // Blocks for several files make up one plan
+                    Some(last)
+                        if last.search_replace
+                            && lines[last.end..start].iter().all(|l| l.trim().is_empty()) =>
+                    {
+                        last.end = close + 1
+                    }
+                    _ => regions.push(Region {
+                        start,
+                        end: close + 1,
+                        search_replace: true,
+                    }),
+                }
+            } else if format != PlanFormat::Markdown {
+                regions.push(Region {
+                    start: i + 1,
+                    end: close,
+                    search_replace: false,
+                });
+            }
+            rest = rest.max(close + 1);
+            i = close + 1;
+            continue;
+        } else if starts_other_dialect(&lines, i) {
+            // Unfenced diff, XML or SEARCH/REPLACE text runs to the end of the gap; the
+            // prose before it is kept as the plan's narrative
+            regions.push(Region {
+                start: rest,
+                end: lines.len(),
+                search_replace: false,
+            });
+            break;
+        }
+        i += 1;
+    }
+    // Nothing recognized: the whole text is offered and kept only if it parses as a plan
+    if regions.is_empty() {
+        return vec![(from, to)];
+    }
+    regions
+        .into_iter()
+        .map(|region| (offsets[region.start], offsets[region.end]))
+        .collect()
+}
+
+// Where a markdown plan starting at `start` ends (exclusive): before the next `# Plan`
+// heading, before the closing fence of the `wrapper` fenced around it, or at the end of
+// the text, past trailing blank lines and fences. None when no ### File header follows,
+// so a lone heading is not taken for a plan.
+fn markdown_end(lines: &[&str], start: usize, wrapper: Option<&Fence>) -> Option<usize> {
+    let mut seen_file = false;
+    // Set after a Search/Content marker, whose code fence is the only kind the parser reads
+    let mut code_next = false;
+    let mut code: Option<Fence> = None;
+    let mut last = start;
+    for (j, line) in lines.iter().enumerate().skip(start) {
+        if let Some(fence) = &code {
+            if fence.closes(line) {
+                code = None;
+            }
+            last = j;
+            continue;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        if is_plan_heading(line) && seen_file {
+            break;
+        }
+        if !code_next && wrapper.is_some_and(|wrapper| wrapper.closes(line)) {
+            break;
+        }
+        if let Some(fence) = Fence::open(line) {
+            if code_next {
+                code = Some(fence);
+                code_next = false;
+                last = j;
+            }
+            continue;
+        }
+        seen_file |= is_file_header(line);
+        code_next =
+            is_code_field(line) || (code_next && !line.trim_start().starts_with(['#', '*']));
+        last = j;
+    }
+    seen_file.then_some(last + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrapped_plans_end_at_the_wrapper() {
+        let reply = "Here you go:\n\n````markdown\n### File a.txt\n### Action create\n#### Change\n**Content**:\n```\nhi\n```\n````\n\nLet me know!\n";
+        let candidates = extract_plans(reply);
+        assert_eq!(candidates.len(), 1);
+        let plan = &candidates[0];
+        assert!(plan.valid);
+        assert!(plan.text.ends_with("```\nhi\n```"), "{}", plan.text);
+        assert_eq!(&reply[plan.start..plan.end], plan.text);
+        assert_eq!((plan.start_line, plan.end_line), (4, 10));
+    }
+
+    #[test]
+    fn finds_each_plan_in_a_reply() {
+        let reply = "Option A:\n```diff\ndiff --git a/x b/x\n--- a/x\n+++ b/x\n@@ -1 +1 @@\n-a\n+b\n```\n\nSome code:\n```rust\nfn main() {}\n```\n\nOption B:\n\n### File q.txt\n### Action delete\n";
+        let candidates = extract_plans(reply);
+        let formats: Vec<_> = candidates.iter().map(|c| c.format).collect();
+        assert_eq!(formats, vec![PlanFormat::UnifiedDiff, PlanFormat::Markdown]);
+        assert!(select_plan(reply, None).is_err());
+        let chosen = select_plan(reply, Some(1)).unwrap();
+        assert_eq!(parse_plan(&chosen).unwrap().file_changes.len(), 1);
+        assert!(select_plan(reply, Some(2)).is_err());
+        assert!(extract_plans("just prose\n```rust\nfn x() {}\n```").is_empty());
+    }
+
+    const HTML_PLAN: &str = "### File a.html\n### Action create\n#### Change\n**Content**:\n```html\n<pre>code</pre>\n<pre>\nblock\n</pre>\n```\n";
+
+    #[test]
+    fn pre_tags_in_code_are_not_wrappers() {
+        let chosen = select_plan(HTML_PLAN, None).unwrap();
+        assert_eq!(chosen, HTML_PLAN.trim_end());
+        assert_eq!(parse_plan(&chosen).unwrap().file_changes.len(), 1);
+    }
+
+    #[test]
+    fn wrapped_plans_keep_pre_tags_in_code() {
+        let reply = format!("Here it is:\n<pre>\n{}</pre>\nDone.\n", HTML_PLAN);
+        let candidates = extract_plans(&reply);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].text, HTML_PLAN.trim_end());
+        assert_eq!(candidates[0].start_line, 3);
+    }
+}
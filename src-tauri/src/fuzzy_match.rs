@@ -0,0 +1,269 @@
+use similar::TextDiff;
+use std::ops::Range;
+
+// Minimum average line similarity for a fuzzy match to be applied without asking
+pub const DEFAULT_FUZZY_THRESHOLD: f64 = 0.9;
+
+#[derive(Debug, Clone)]
+pub struct FuzzyCandidate {
+    pub range: Range<usize>,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub similarity: f64,
+}
+
+fn normalize_line(line: &str) -> String {
+    line.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn line_similarity(a: &str, b: &str) -> f64 {
+    if a == b {
+        return 1.0;
+    }
+    TextDiff::from_chars(a, b).ratio() as f64
+}
+
+// Byte range of every line in `content`, excluding the line terminator
+fn line_ranges(content: &str) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut offset = 0;
+    for line in content.split_inclusive('\n') {
+        let body = line.trim_end_matches(['\n', '\r']);
+        ranges.push(offset..offset + body.len());
+        offset += line.len();
+    }
+    ranges
+}
+
+// Character counts of a line in a few buckets. Characters sharing a bucket only make the
+// overlap look larger, so comparing profiles never underestimates similarity.
+struct LineProfile {
+    len: usize,
+    buckets: [u32; 32],
+}
+
+impl LineProfile {
+    fn new(line: &str) -> Self {
+        let mut buckets = [0; 32];
+        let mut len = 0;
+        for c in line.chars() {
+            buckets[c as usize % 32] += 1;
+            len += 1;
+        }
+        LineProfile { len, buckets }
+    }
+
+    // Upper bound of line_similarity: the character diff can never match more characters
+    // than the two lines have in common
+    fn similarity_bound(&self, other: &LineProfile) -> f64 {
+        let total = self.len + other.len;
+        if total == 0 {
+            return 1.0;
+        }
+        let common: u32 = self
+            .buckets
+            .iter()
+            .zip(&other.buckets)
+            .map(|(a, b)| (*a).min(*b))
+            .sum();
+        2.0 * common as f64 / total as f64
+    }
+}
+
+// Float noise between the bound and the diff's own ratio
+const BOUND_SLACK: f64 = 1e-6;
+
+// Every position of a window with as many lines as the search text. Positions get a cheap
+// upper bound up front; the character diff only runs for the ones that can still matter.
+struct Windows {
+    search_lines: Vec<String>,
+    file_lines: Vec<String>,
+    file_ranges: Vec<Range<usize>>,
+    bounds: Vec<f64>,
+}
+
+impl Windows {
+    // None when the search text is blank or longer than the file
+    fn new(content: &str, search: &str) -> Option<Self> {
+        let search_lines: Vec<String> = search
+            .trim_matches('\n')
+            .lines()
+            .map(normalize_line)
+            .collect();
+        if search_lines.iter().all(|line| line.is_empty()) {
+            return None;
+        }
+        let file_ranges = line_ranges(content);
+        let file_lines: Vec<String> = file_ranges
+            .iter()
+            .map(|range| normalize_line(&content[range.clone()]))
+            .collect();
+        let window = search_lines.len();
+        if file_lines.len() < window {
+            return None;
+        }
+        let search_profiles: Vec<LineProfile> =
+            search_lines.iter().map(|l| LineProfile::new(l)).collect();
+        let file_profiles: Vec<LineProfile> =
+            file_lines.iter().map(|l| LineProfile::new(l)).collect();
+        let bounds = (0..=file_lines.len() - window)
+            .map(|start| {
+                let total: f64 = search_profiles
+                    .iter()
+                    .zip(&file_profiles[start..start + window])
+                    .map(|(a, b)| a.similarity_bound(b))
+                    .sum();
+                total / window as f64
+            })
+            .collect();
+        Some(Windows {
+            search_lines,
+            file_lines,
+            file_ranges,
+            bounds,
+        })
+    }
+
+    // Mean similarity of the whitespace-normalized lines of the window at `start`
+    fn score(&self, start: usize) -> f64 {
+        let window = self.search_lines.len();
+        let total: f64 = self
+            .search_lines
+            .iter()
+            .zip(&self.file_lines[start..start + window])
+            .map(|(a, b)| line_similarity(a, b))
+            .sum();
+        total / window as f64
+    }
+
+    fn candidate(&self, start: usize, similarity: f64) -> FuzzyCandidate {
+        let end = start + self.search_lines.len() - 1;
+        FuzzyCandidate {
+            range: self.file_ranges[start].start..self.file_ranges[end].end,
+            start_line: start + 1,
+            end_line: end + 1,
+            similarity,
+        }
+    }
+}
+
+// Slides a window with as many lines as the search text over the file and scores each
+// position by the mean similarity of its whitespace-normalized lines. Returns the
+// non-overlapping regions at or above `threshold`, highest similarity first.
+pub fn find_candidates(content: &str, search: &str, threshold: f64) -> Vec<FuzzyCandidate> {
+    let Some(windows) = Windows::new(content, search) else {
+        return Vec::new();
+    };
+    let mut scored: Vec<(usize, f64)> = windows
+        .bounds
+        .iter()
+        .enumerate()
+        .filter(|(_, bound)| **bound + BOUND_SLACK >= threshold)
+        .map(|(start, _)| (start, windows.score(start)))
+        .filter(|(_, similarity)| *similarity >= threshold)
+        .collect();
+    scored.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let mut candidates: Vec<FuzzyCandidate> = Vec::new();
+    for (start, similarity) in scored {
+        let candidate = windows.candidate(start, similarity);
+        if candidates
+            .iter()
+            .any(|c| candidate.start_line <= c.end_line && c.start_line <= candidate.end_line)
+        {
+            continue;
+        }
+        candidates.push(candidate);
+    }
+    candidates
+}
+
+// The most similar region however low its score, for the error message. Positions are
+// tried best bound first, so the search stops once no bound can beat the best score.
+pub fn closest_candidate(content: &str, search: &str) -> Option<FuzzyCandidate> {
+    let windows = Windows::new(content, search)?;
+    let mut order: Vec<usize> = (0..windows.bounds.len()).collect();
+    order.sort_by(|&a, &b| {
+        windows.bounds[b]
+            .total_cmp(&windows.bounds[a])
+            .then(a.cmp(&b))
+    });
+    let mut best: Option<(usize, f64)> = None;
+    for start in order {
+        if best.is_some_and(|(_, score)| windows.bounds[start] + BOUND_SLACK < score) {
+            break;
+        }
+        let score = windows.score(start);
+        let better = best.is_none_or(|(best_start, best_score)| {
+            score > best_score || (score == best_score && start < best_start)
+        });
+        if better {
+            best = Some((start, score));
+        }
+    }
+    best.map(|(start, score)| windows.candidate(start, score))
+}
+
+// Human readable report of the closest region, meant to be pasted back to the model
+pub fn describe_closest(content: &str, search: &str, candidate: &FuzzyCandidate) -> String {
+    // Both sides end in a newline so the diff is not cluttered with EOF markers
+    let expected = format!("{}\n", search.trim_matches('\n'));
+    let actual = format!("{}\n", &content[candidate.range.clone()]);
+    let diff = TextDiff::from_lines(&expected, &actual)
+        .unified_diff()
+        .context_radius(3)
+        .header("search", "file")
+        .to_string();
+    format!(
+        "Search block not found. Closest match at lines {}-{} (similarity {:.2}):\n{}",
+        candidate.start_line, candidate.end_line, candidate.similarity, diff
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENT: &str = "fn main() {\n    let total = add(1, 2);\n    println!(\"{}\", total);\n}\n\nfn other() {\n    let total = add(3, 4);\n    println!(\"{}\", total);\n}\n";
+
+    #[test]
+    fn keeps_regions_above_the_threshold() {
+        let search = "let total = add(1, 2);\nprintln!(\"{}\", totl);";
+        let candidates = find_candidates(CONTENT, search, DEFAULT_FUZZY_THRESHOLD);
+        assert_eq!(candidates.len(), 2);
+        // The region that also matches the numbers wins
+        assert_eq!((candidates[0].start_line, candidates[0].end_line), (2, 3));
+        assert_eq!(candidates[1].start_line, 7);
+        assert!(candidates[0].similarity > candidates[1].similarity);
+        assert_eq!(
+            &CONTENT[candidates[0].range.clone()],
+            "    let total = add(1, 2);\n    println!(\"{}\", total);"
+        );
+
+        assert!(find_candidates(CONTENT, search, 0.99).is_empty());
+        assert!(find_candidates(CONTENT, "\n\n", 0.5).is_empty());
+        assert!(find_candidates("one line", "a\nb", 0.0).is_empty());
+    }
+
+    #[test]
+    fn closest_match_is_described() {
+        let search = "let sum = sub(1, 2);\nprint(sum);";
+        assert!(find_candidates(CONTENT, search, DEFAULT_FUZZY_THRESHOLD).is_empty());
+        let best = closest_candidate(CONTENT, search).unwrap();
+        assert_eq!((best.start_line, best.end_line), (2, 3));
+        let exhaustive = (0..CONTENT.lines().count() - 1)
+            .map(|start| Windows::new(CONTENT, search).unwrap().score(start))
+            .fold(0.0, f64::max);
+        assert_eq!(best.similarity, exhaustive);
+
+        let message = describe_closest(CONTENT, search, &best);
+        let expected = format!(
+            "Search block not found. Closest match at lines 2-3 (similarity {:.2}):\n\
+             --- search\n+++ file\n@@ -1,2 +1,2 @@\n\
+             -let sum = sub(1, 2);\n-print(sum);\n\
+             +    let total = add(1, 2);\n+    println!(\"{{}}\", total);\n",
+            best.similarity
+        );
+        assert_eq!(message, expected);
+    }
+}
@@ -0,0 +1,133 @@
+use anyhow::{anyhow, Result};
+use std::path::Path;
+use std::process::Command;
+
+fn git(root: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git").args(args).current_dir(root).output()?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    } else {
+        Err(anyhow!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+// Path relative to the top of the work tree, as the index stores it. `path` itself may
+// no longer exist (the source of a move), so only its parent is canonicalized.
+fn index_path(top: &Path, path: &Path) -> Result<String> {
+    let parent = path
+        .parent()
+        .ok_or_else(|| anyhow!("Invalid path: {}", path.display()))?
+        .canonicalize()?;
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid path: {}", path.display()))?;
+    let relative = parent
+        .join(name)
+        .strip_prefix(top)
+        .map_err(|_| anyhow!("{} is outside the git work tree", path.display()))?
+        .to_path_buf();
+    relative
+        .to_str()
+        .map(|p| p.replace('\\', "/"))
+        .ok_or_else(|| anyhow!("Non UTF-8 path cannot be staged: {}", path.display()))
+}
+
+// Records an already-performed move in the index the way `git mv` would: each index entry
+// moves to the new path with its original blob, so edits made to the moved file stay
+// unstaged. A moved directory takes every tracked file below it along. Returns false when
+// the project is not a git repository or nothing moved was tracked.
+pub fn stage_rename(root: &Path, from: &Path, to: &Path) -> Result<bool> {
+    let Ok(top) = git(root, &["rev-parse", "--show-toplevel"]) else {
+        return Ok(false);
+    };
+    let top = Path::new(top.trim()).canonicalize()?;
+    let from = index_path(&top, from)?;
+    let to = index_path(&top, to)?;
+    // "<mode> <blob> <stage>\t<path>"
+    let listing = git(&top, &["ls-files", "--stage", "--", &from])?;
+    let mut moved: Vec<(String, String)> = Vec::new();
+    let mut add = vec!["update-index".to_string(), "--add".to_string()];
+    for line in listing.lines() {
+        let Some((info, path)) = line.split_once('\t') else {
+            continue;
+        };
+        let Some(rest) = path.strip_prefix(&from) else {
+            continue;
+        };
+        if !rest.is_empty() && !rest.starts_with('/') {
+            continue;
+        }
+        let mut fields = info.split_whitespace();
+        let (Some(mode), Some(blob)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        add.push("--cacheinfo".to_string());
+        add.push(format!("{},{},{}{}", mode, blob, to, rest));
+        moved.push((path.to_string(), format!("{}{}", to, rest)));
+    }
+    if moved.is_empty() {
+        return Ok(false);
+    }
+    git(&top, &add.iter().map(String::as_str).collect::<Vec<_>>())?;
+    let mut remove = vec!["update-index", "--force-remove", "--"];
+    remove.extend(moved.iter().map(|(path, _)| path.as_str()));
+    git(&top, &remove)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch_dir;
+    use std::fs;
+
+    fn repo(name: &str) -> std::path::PathBuf {
+        let root = scratch_dir(name);
+        git(&root, &["init", "-q"]).unwrap();
+        fs::create_dir(root.join("dir")).unwrap();
+        fs::write(root.join("a.txt"), "a\n").unwrap();
+        fs::write(root.join("dir/b.txt"), "b\n").unwrap();
+        git(&root, &["add", "."]).unwrap();
+        root
+    }
+
+    fn staged(root: &Path) -> String {
+        git(root, &["ls-files"]).unwrap()
+    }
+
+    #[test]
+    fn moves_the_index_entry_of_a_file() {
+        let root = repo("git-rename-file");
+        fs::write(root.join("a.txt"), "edited\n").unwrap();
+        fs::rename(root.join("a.txt"), root.join("c.txt")).unwrap();
+        assert!(stage_rename(&root, &root.join("a.txt"), &root.join("c.txt")).unwrap());
+        assert_eq!(staged(&root), "c.txt\ndir/b.txt\n");
+        // The edit stays unstaged
+        let diff = git(&root, &["diff", "--name-only"]).unwrap();
+        assert_eq!(diff, "c.txt\n");
+    }
+
+    #[test]
+    fn moves_every_entry_of_a_directory() {
+        let root = repo("git-rename-dir");
+        fs::rename(root.join("dir"), root.join("moved")).unwrap();
+        assert!(stage_rename(&root, &root.join("dir"), &root.join("moved")).unwrap());
+        assert_eq!(staged(&root), "a.txt\nmoved/b.txt\n");
+    }
+
+    #[test]
+    fn ignores_untracked_files_and_plain_directories() {
+        let root = repo("git-rename-untracked");
+        fs::write(root.join("new.txt"), "n\n").unwrap();
+        fs::rename(root.join("new.txt"), root.join("newer.txt")).unwrap();
+        assert!(!stage_rename(&root, &root.join("new.txt"), &root.join("newer.txt")).unwrap());
+
+        let plain = scratch_dir("git-rename-plain");
+        fs::write(plain.join("x"), "").unwrap();
+        assert!(!stage_rename(&plain, &plain.join("x"), &plain.join("y")).unwrap());
+    }
+}
@@ -0,0 +1,48 @@
+use sha2::{Digest, Sha256};
+use std::fs;
+use tauri::command;
+
+// Length of the hash embedded in prompts and echoed back in `### Hash` lines
+pub const SHORT_HASH_LEN: usize = 12;
+
+// Shortest `### Hash` prefix accepted; anything shorter matches too many files to show
+// that the file is the one the model saw
+pub const MIN_HASH_LEN: usize = 8;
+
+pub fn content_hash(bytes: &[u8]) -> String {
+    let digest = Sha256::digest(bytes);
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn short_hash(bytes: &[u8]) -> String {
+    let mut hash = content_hash(bytes);
+    hash.truncate(SHORT_HASH_LEN);
+    hash
+}
+
+// Whether a hash taken from a plan is a long enough prefix of `actual`
+pub fn hash_matches(expected: &str, actual: &str) -> bool {
+    expected.len() >= MIN_HASH_LEN && actual.starts_with(expected)
+}
+
+#[command]
+pub fn content_hash_path(path: &str) -> Result<String, String> {
+    let bytes = fs::read(path).map_err(|e| format!("Failed to read file: {}", e))?;
+    Ok(short_hash(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_only_long_enough_prefixes() {
+        let full = content_hash(b"hello\n");
+        assert!(hash_matches(&full, &full));
+        assert!(hash_matches(&short_hash(b"hello\n"), &full));
+        assert!(hash_matches(&full[..MIN_HASH_LEN], &full));
+        assert!(!hash_matches(&full[..MIN_HASH_LEN - 1], &full));
+        assert!(!hash_matches("", &full));
+        assert!(!hash_matches(&short_hash(b"other\n"), &full));
+    }
+}
@@ -0,0 +1,379 @@
+use crate::atomic_write::{atomic_write, file_mode};
+use crate::change_types::{FileError, UndoReport};
+use crate::dir_actions::list_tree;
+use crate::hash_utils::content_hash;
+use anyhow::{anyhow, Context, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Older entries beyond this count are pruned whenever a new apply is recorded
+const MAX_ENTRIES: usize = 50;
+const ENTRY_FILE: &str = "entry.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalFile {
+    pub path: PathBuf,
+    // Name of the blob holding the original bytes; None when the file did not exist
+    pub original: Option<String>,
+    // Hash of what the apply left on disk; None when the apply removed the file
+    #[serde(rename = "appliedHash")]
+    pub applied_hash: Option<String>,
+    pub undone: bool,
+    // Permission bits of the original file, restored along with its bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub id: String,
+    pub timestamp: u64,
+    pub plan: String,
+    pub files: Vec<JournalFile>,
+}
+
+// Undo history of applied plans, one directory per apply under the app data dir:
+// <dir>/<id>/entry.json plus one blob per touched file holding its original bytes.
+pub struct Journal {
+    dir: PathBuf,
+}
+
+impl Journal {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    // Ids come from the UI, so anything but the digits and hyphens begin() generates is
+    // refused before it can name a path outside the journal
+    fn entry_dir(&self, id: &str) -> Result<PathBuf> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit() || c == '-') {
+            return Err(anyhow!("Invalid journal entry id: {}", id));
+        }
+        Ok(self.dir.join(id))
+    }
+
+    // Snapshots the original state of every path before anything is written, so the
+    // entry survives even if the app dies mid-apply.
+    pub fn begin(&self, plan: &str, paths: &[PathBuf]) -> Result<JournalEntry> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .context("System clock is before the Unix epoch")?;
+        let mut id = now.as_millis().to_string();
+        let mut suffix = 1;
+        while self.entry_dir(&id)?.exists() {
+            id = format!("{}-{}", now.as_millis(), suffix);
+            suffix += 1;
+        }
+        let entry_dir = self.entry_dir(&id)?;
+        fs::create_dir_all(&entry_dir).context(format!(
+            "Could not create journal directory: {}",
+            entry_dir.display()
+        ))?;
+
+        let mut files = Vec::new();
+        for (i, path) in paths.iter().enumerate() {
+            let original = if path.is_file() {
+                let bytes = fs::read(path)
+                    .context(format!("Could not snapshot file: {}", path.display()))?;
+                let blob = format!("{}.orig", i);
+                fs::write(entry_dir.join(&blob), bytes).context(format!(
+                    "Could not write journal blob for: {}",
+                    path.display()
+                ))?;
+                Some(blob)
+            } else {
+                None
+            };
+            files.push(JournalFile {
+                path: path.clone(),
+                mode: original.as_ref().and_then(|_| file_mode(path)),
+                original,
+                applied_hash: None,
+                undone: false,
+            });
+        }
+        let entry = JournalEntry {
+            id,
+            timestamp: now.as_secs(),
+            plan: plan.to_string(),
+            files,
+        };
+        self.save(&entry)?;
+        debug!("Journal::begin - Recorded entry {}", entry.id);
+        Ok(entry)
+    }
+
+    // Keeps only the paths that were actually changed and records their new state.
+    // Drops the entry entirely when nothing was applied.
+    pub fn finish(
+        &self,
+        mut entry: JournalEntry,
+        applied: &HashSet<PathBuf>,
+    ) -> Result<Option<String>> {
+        entry.files.retain(|f| applied.contains(&f.path));
+        if entry.files.is_empty() {
+            self.remove(&entry.id)?;
+            return Ok(None);
+        }
+        for file in entry.files.iter_mut() {
+            file.applied_hash = state_hash(&file.path);
+        }
+        self.save(&entry)?;
+        self.prune()?;
+        Ok(Some(entry.id))
+    }
+
+    // Records what is on disk now as the applied state of every file not yet undone.
+    // For callers that post-process the files an apply wrote (the UI formats them), so
+    // undo does not mistake that for a later edit.
+    pub fn refresh(&self, id: &str) -> Result<()> {
+        let mut entry = self.load(id)?;
+        for file in entry.files.iter_mut().filter(|file| !file.undone) {
+            file.applied_hash = state_hash(&file.path);
+        }
+        self.save(&entry)
+    }
+
+    fn save(&self, entry: &JournalEntry) -> Result<()> {
+        let path = self.entry_dir(&entry.id)?.join(ENTRY_FILE);
+        let json = serde_json::to_string_pretty(entry)?;
+        fs::write(&path, json).context(format!("Could not write journal entry: {}", path.display()))
+    }
+
+    fn remove(&self, id: &str) -> Result<()> {
+        let dir = self.entry_dir(id)?;
+        fs::remove_dir_all(&dir)
+            .context(format!("Could not remove journal entry: {}", dir.display()))
+    }
+
+    fn load(&self, id: &str) -> Result<JournalEntry> {
+        let path = self.entry_dir(id)?.join(ENTRY_FILE);
+        let json = fs::read_to_string(&path).context(format!("No journal entry with id {}", id))?;
+        serde_json::from_str(&json).context(format!("Corrupt journal entry: {}", path.display()))
+    }
+
+    // Newest first
+    pub fn list(&self) -> Result<Vec<JournalEntry>> {
+        if !self.dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut entries = Vec::new();
+        for dent in fs::read_dir(&self.dir)? {
+            let dent = dent?;
+            if !dent.path().join(ENTRY_FILE).exists() {
+                continue;
+            }
+            let id = dent.file_name().to_string_lossy().into_owned();
+            match self.load(&id) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => log::warn!("Skipping journal entry {}: {}", id, e),
+            }
+        }
+        entries.sort_by(|a, b| b.timestamp.cmp(&a.timestamp).then(b.id.cmp(&a.id)));
+        Ok(entries)
+    }
+
+    fn prune(&self) -> Result<()> {
+        for entry in self.list()?.into_iter().skip(MAX_ENTRIES) {
+            self.remove(&entry.id)?;
+        }
+        Ok(())
+    }
+
+    // Restores the original bytes of every file in the entry (or only `only_path`).
+    // Files edited since the apply are reported as conflicts and left alone unless `force`.
+    pub fn undo(&self, id: &str, only_path: Option<&Path>, force: bool) -> Result<UndoReport> {
+        let mut entry = self.load(id)?;
+        let entry_dir = self.entry_dir(id)?;
+        let mut report = UndoReport::default();
+        let mut matched = false;
+
+        for file in entry.files.iter_mut() {
+            if only_path.is_some_and(|p| p != file.path) {
+                continue;
+            }
+            matched = true;
+            if file.undone {
+                report.conflicts.push(FileError {
+                    path: file.path.clone(),
+                    messages: vec!["Already undone".to_string()],
+                    changes: Vec::new(),
+                });
+                continue;
+            }
+            if state_hash(&file.path) != file.applied_hash && !force {
+                report.conflicts.push(FileError {
+                    path: file.path.clone(),
+                    messages: vec![
+                        "File has changed since the plan was applied; undo with force to overwrite"
+                            .to_string(),
+                    ],
+                    changes: Vec::new(),
+                });
+                continue;
+            }
+            let restored = match &file.original {
+                Some(blob) => {
+                    let bytes = fs::read(entry_dir.join(blob))
+                        .context(format!("Missing journal blob for: {}", file.path.display()))?;
+                    if let Some(parent) = file.path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    atomic_write(&file.path, &bytes, file.mode)
+                }
+                // Directories created by create-dir or copy
+                None if file.path.is_dir() => fs::remove_dir_all(&file.path).map_err(Into::into),
+                None if file.path.exists() => fs::remove_file(&file.path).map_err(Into::into),
+                None => Ok(()),
+            };
+            match restored {
+                Ok(()) => {
+                    file.undone = true;
+                    report.restored.push(file.path.clone());
+                }
+                Err(err) => report.conflicts.push(FileError {
+                    path: file.path.clone(),
+                    messages: vec![format!("Could not restore file: {:#}", err)],
+                    changes: Vec::new(),
+                }),
+            }
+        }
+
+        if let Some(path) = only_path {
+            if !matched {
+                return Err(anyhow!(
+                    "File {} is not part of journal entry {}",
+                    path.display(),
+                    id
+                ));
+            }
+        }
+        self.save(&entry)?;
+        Ok(report)
+    }
+}
+
+// Fingerprint of what is at `path`: the bytes of a file, or the entry names of a
+// directory, so undo notices files added to or removed from a created directory
+fn state_hash(path: &Path) -> Option<String> {
+    if path.is_dir() {
+        let listing = list_tree(path).ok()?;
+        let names: Vec<String> = listing
+            .iter()
+            .map(|entry| entry.to_string_lossy().into_owned())
+            .collect();
+        return Some(content_hash(names.join("\n").as_bytes()));
+    }
+    fs::read(path).ok().map(|bytes| content_hash(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch_dir;
+
+    // An entry for `a.txt`, applied as "applied\n", plus the new file `b.txt`
+    fn applied(name: &str) -> (PathBuf, Journal, String) {
+        let dir = scratch_dir(name);
+        let journal = Journal::new(dir.join("journal"));
+        let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+        fs::write(&a, "original\n").unwrap();
+        let entry = journal
+            .begin("plan", &[a.clone(), b.clone(), dir.join("c.txt")])
+            .unwrap();
+        fs::write(&a, "applied\n").unwrap();
+        fs::write(&b, "new\n").unwrap();
+        let id = journal
+            .finish(entry, &HashSet::from([a, b]))
+            .unwrap()
+            .unwrap();
+        (dir, journal, id)
+    }
+
+    #[test]
+    fn undo_restores_originals() {
+        let (dir, journal, id) = applied("journal-undo");
+        assert_eq!(journal.list().unwrap()[0].files.len(), 2);
+        let report = journal.undo(&id, None, false).unwrap();
+        assert_eq!(report.restored.len(), 2);
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "original\n");
+        assert!(!dir.join("b.txt").exists());
+
+        let again = journal.undo(&id, None, false).unwrap();
+        assert!(again.restored.is_empty());
+        assert_eq!(again.conflicts.len(), 2);
+    }
+
+    #[test]
+    fn undo_refuses_later_edits_unless_forced() {
+        let (dir, journal, id) = applied("journal-conflict");
+        fs::write(dir.join("a.txt"), "edited\n").unwrap();
+        let report = journal.undo(&id, Some(&dir.join("a.txt")), false).unwrap();
+        assert_eq!(report.conflicts.len(), 1);
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "edited\n");
+
+        let report = journal.undo(&id, Some(&dir.join("a.txt")), true).unwrap();
+        assert_eq!(report.restored, vec![dir.join("a.txt")]);
+        assert!(journal.undo(&id, Some(&dir.join("c.txt")), false).is_err());
+    }
+
+    #[test]
+    fn refresh_accepts_formatting_after_apply() {
+        let (dir, journal, id) = applied("journal-refresh");
+        fs::write(dir.join("a.txt"), "applied;\n").unwrap();
+        journal.refresh(&id).unwrap();
+        let report = journal.undo(&id, None, false).unwrap();
+        assert!(report.conflicts.is_empty(), "{:?}", report.conflicts);
+        assert_eq!(fs::read_to_string(dir.join("a.txt")).unwrap(), "original\n");
+    }
+
+    #[test]
+    fn finish_drops_entries_that_applied_nothing() {
+        let dir = scratch_dir("journal-empty");
+        let journal = Journal::new(dir.join("journal"));
+        let entry = journal.begin("plan", &[dir.join("a.txt")]).unwrap();
+        assert_eq!(journal.finish(entry, &HashSet::new()).unwrap(), None);
+        assert!(journal.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_ids_that_are_not_entries() {
+        let (dir, journal, _) = applied("journal-ids");
+        fs::create_dir_all(dir.join("elsewhere")).unwrap();
+        for id in ["../elsewhere", "..", "", "1/../2", "abc"] {
+            assert!(journal.undo(id, None, false).is_err(), "{}", id);
+            assert!(journal.refresh(id).is_err(), "{}", id);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn undo_restores_permissions() {
+        use crate::atomic_write::set_mode;
+        let dir = scratch_dir("journal-mode");
+        let journal = Journal::new(dir.join("journal"));
+        let (script, changed) = (dir.join("run.sh"), dir.join("tool.sh"));
+        for path in [&script, &changed] {
+            fs::write(path, "#!/bin/sh\n").unwrap();
+            set_mode(path, 0o755).unwrap();
+        }
+        let entry = journal
+            .begin("plan", &[script.clone(), changed.clone()])
+            .unwrap();
+        fs::remove_file(&script).unwrap();
+        set_mode(&changed, 0o644).unwrap();
+        let id = journal
+            .finish(entry, &HashSet::from([script.clone(), changed.clone()]))
+            .unwrap()
+            .unwrap();
+        let report = journal.undo(&id, None, false).unwrap();
+        assert!(report.conflicts.is_empty(), "{:?}", report.conflicts);
+        for path in [&script, &changed] {
+            assert_eq!(file_mode(path).map(|mode| mode & 0o777), Some(0o755));
+        }
+    }
+}
@@ -1,21 +1,149 @@
 mod apply_changes;
 mod apply_file_change;
+mod atomic_write;
 mod change_types;
+mod dir_actions;
+mod editorconfig;
+mod extract_plans;
 mod fs_api;
+mod fuzzy_match;
+mod git_utils;
+mod hash_utils;
+mod journal;
 mod parse_change_protocol;
+mod parse_plan;
+mod parse_search_replace;
+mod parse_unified_diff;
+mod parse_xml_protocol;
+mod preview_changes;
+mod sandbox_policy;
+mod select_changes;
+mod serialize_plan;
+#[cfg(test)]
+mod test_support;
+mod text_encoding;
 mod token_utils;
+mod transaction;
+use change_types::{ApplyOptions, PlanEdit};
 use fs_api::{list_directory, search_config_files, search_files, start_watch};
 
+use journal::Journal;
+use parse_plan::PlanFormat;
 use serde_json::{json, Value};
+use std::path::PathBuf;
+use tauri::Manager;
+
+fn apply_journal(app: &tauri::AppHandle) -> Result<Journal, String> {
+    let dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|e| format!("Failed to resolve app data directory: {}", e))?;
+    Ok(Journal::new(dir.join("journal")))
+}
 
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 #[tauri::command]
-fn apply_protocol(xml_input: &str) -> Result<Value, String> {
-    match crate::apply_changes::apply_changes(xml_input) {
-        Ok((successes, errors)) => Ok(json!({ "success": successes, "errors": errors })),
+fn apply_protocol(
+    app: tauri::AppHandle,
+    xml_input: &str,
+    options: Option<ApplyOptions>,
+) -> Result<Value, String> {
+    let options = options.unwrap_or_default();
+    let journal = apply_journal(&app)
+        .inspect_err(|e| log::error!("Apply journal disabled: {}", e))
+        .ok();
+    match crate::apply_changes::apply_changes(xml_input, &options, journal.as_ref()) {
+        Ok(report) => Ok(json!(report)),
+        Err(e) => {
+            sentry::capture_error(&*e);
+            Err(format!("Failed to apply changes: {:#}", e))
+        }
+    }
+}
+
+#[tauri::command]
+fn preview_protocol(xml_input: &str, options: Option<ApplyOptions>) -> Result<Value, String> {
+    let options = options.unwrap_or_default();
+    match crate::preview_changes::preview_changes(xml_input, &options) {
+        Ok(report) => Ok(json!(report)),
+        Err(e) => {
+            sentry::capture_error(&*e);
+            Err(format!("Failed to preview changes: {:#}", e))
+        }
+    }
+}
+
+#[tauri::command]
+fn validate_protocol(xml_input: &str) -> Result<Value, String> {
+    Ok(json!(crate::parse_plan::validate_plan(xml_input)))
+}
+
+// Candidate plans in pasted text such as a whole chat reply; apply and preview take the
+// chosen one's index as `planIndex`
+#[tauri::command]
+fn extract_protocols(text: &str) -> Result<Value, String> {
+    Ok(json!({ "plans": crate::extract_plans::extract_plans(text) }))
+}
+
+#[tauri::command]
+fn summarize_protocol(xml_input: &str, plan_index: Option<usize>) -> Result<Value, String> {
+    // The same plan apply would pick, so the ids line up with `include`
+    let summary = crate::extract_plans::select_plan(xml_input, plan_index)
+        .and_then(|plan| crate::parse_plan::summarize_plan(&plan));
+    match summary {
+        Ok(summary) => Ok(json!(summary)),
+        Err(e) => Err(format!("Failed to parse plan: {:#}", e)),
+    }
+}
+
+// Deletes or edits individual changes and returns the rewritten plan, in `format` or
+// the dialect the plan came in
+#[tauri::command]
+fn edit_protocol(
+    xml_input: &str,
+    edits: Vec<PlanEdit>,
+    format: Option<PlanFormat>,
+) -> Result<Value, String> {
+    match crate::serialize_plan::edit_plan(xml_input, &edits, format) {
+        Ok(edited) => Ok(json!(edited)),
+        Err(e) => Err(format!("Failed to edit plan: {:#}", e)),
+    }
+}
+
+#[tauri::command]
+fn list_apply_journal(app: tauri::AppHandle) -> Result<Value, String> {
+    let journal = apply_journal(&app)?;
+    match journal.list() {
+        Ok(entries) => Ok(json!({ "entries": entries })),
+        Err(e) => Err(format!("Failed to read apply journal: {}", e)),
+    }
+}
+
+// Called after the UI has formatted the applied files, so undo treats the formatted
+// contents as what the apply left
+#[tauri::command]
+fn refresh_apply_journal(app: tauri::AppHandle, id: &str) -> Result<Value, String> {
+    let journal = apply_journal(&app)?;
+    match journal.refresh(id) {
+        Ok(()) => Ok(json!({ "id": id })),
+        Err(e) => Err(format!("Failed to update apply journal: {}", e)),
+    }
+}
+
+#[tauri::command]
+fn undo_apply(
+    app: tauri::AppHandle,
+    id: &str,
+    path: Option<String>,
+    force: Option<bool>,
+) -> Result<Value, String> {
+    let journal = apply_journal(&app)?;
+    let path = path.map(PathBuf::from);
+    match journal.undo(id, path.as_deref(), force.unwrap_or(false)) {
+        Ok(report) => Ok(json!(report)),
         Err(e) => {
             sentry::capture_error(&*e);
-            Err(format!("Failed to apply changes: {}", e))
+            Err(format!("Failed to undo apply: {}", e))
         }
     }
 }
@@ -122,8 +250,17 @@ pub fn run() {
         })
         .invoke_handler(tauri::generate_handler![
             apply_protocol,
+            preview_protocol,
+            validate_protocol,
+            edit_protocol,
+            summarize_protocol,
+            extract_protocols,
+            list_apply_journal,
+            refresh_apply_journal,
+            undo_apply,
             token_utils::count_tokens,
             token_utils::count_tokens_path,
+            hash_utils::content_hash_path,
             list_directory,
             search_config_files,
             search_files,
@@ -1,195 +1,784 @@
-use crate::change_types::{Action, Change, FileChange};
+use crate::change_types::{Action, Change, Diagnostic, FileChange, Occurrence, Severity};
+use crate::hash_utils::MIN_HASH_LEN;
 use anyhow::{anyhow, Result};
+use std::fmt;
 use std::path::PathBuf;
 
-pub fn parse_change_protocol(xml_protocol: &str) -> Result<Vec<FileChange>> {
+const HEADERS: [&str; 5] = ["File", "Action", "Mode", "Hash", "To"];
+const FIELDS: [&str; 4] = ["Description", "Search", "Content", "Occurrence"];
+
+// Spellings models use for actions, mapped to the ones the protocol defines
+const ACTION_ALIASES: [(&str, &str); 11] = [
+    ("edit", "modify"),
+    ("update", "modify"),
+    ("change", "modify"),
+    ("add", "create"),
+    ("new", "create"),
+    ("remove", "delete"),
+    ("replace", "rewrite"),
+    ("overwrite", "rewrite"),
+    ("mkdir", "create-dir"),
+    ("rmdir", "delete-dir"),
+    ("mv", "rename"),
+];
+
+// Strips the backticks, quotes and bold that models like to put around paths and actions
+fn unwrap_value(value: &str) -> &str {
+    value
+        .trim()
+        .trim_start_matches("**")
+        .trim_end_matches("**")
+        .trim_matches(|c| c == '`' || c == '"' || c == '\'')
+        .trim()
+}
+
+// `word` at the start of `text`, ignoring case; returns the canonical spelling and the rest
+fn strip_keyword<'a>(text: &'a str, keywords: &[&'static str]) -> Option<(&'static str, &'a str)> {
+    keywords.iter().find_map(|keyword| {
+        let head = text.get(..keyword.len())?;
+        head.eq_ignore_ascii_case(keyword)
+            .then(|| (*keyword, &text[keyword.len()..]))
+    })
+}
+
+// Rewrites near-misses of a header or field marker into the canonical form: indentation,
+// "### File: path", a quoted or backticked path, "**Search:**", a capitalized or aliased
+// action. None when the line is already canonical or is not a header at all.
+fn normalize_line(line: &str) -> Option<String> {
+    let trimmed = line.trim_start();
+    let canonical = if let Some(rest) = trimmed.strip_prefix("####") {
+        let (_, rest) = strip_keyword(rest.trim_start(), &["Change"])?;
+        format!("#### Change{}", rest)
+    } else if let Some(rest) = trimmed.strip_prefix("###") {
+        let (header, rest) = strip_keyword(rest.trim_start(), &HEADERS)?;
+        if !rest.starts_with([':', ' ', '\t']) {
+            return None;
+        }
+        let value = unwrap_value(rest.trim_start().trim_start_matches(':'));
+        if value.is_empty() {
+            return None;
+        }
+        let value = match header {
+            "Action" => {
+                let action = value.to_lowercase().replace(['_', ' '], "-");
+                match ACTION_ALIASES.iter().find(|(alias, _)| *alias == action) {
+                    Some((_, action)) => action.to_string(),
+                    None => action,
+                }
+            }
+            _ => value.to_string(),
+        };
+        format!("### {} {}", header, value)
+    } else if let Some(rest) = trimmed.strip_prefix("**") {
+        let (field, rest) = strip_keyword(rest, &FIELDS)?;
+        let value = ["**:", ":**", "** :"]
+            .iter()
+            .find_map(|marker| rest.strip_prefix(marker))
+            .or_else(|| (rest == "**").then_some(""))?;
+        format!("**{}**: {}", field, value.trim())
+            .trim_end()
+            .to_string()
+    } else {
+        return None;
+    };
+    (canonical != line).then_some(canonical)
+}
+
+// Whether a line opens a file block, in any of the spellings normalize_line accepts
+pub fn is_file_header(line: &str) -> bool {
+    let line = normalize_line(line).unwrap_or_else(|| line.to_string());
+    line.starts_with("### File ")
+}
+
+// Whether a line is a **Search** or **Content** marker, the fields whose code fence follows
+pub fn is_code_field(line: &str) -> bool {
+    let line = normalize_line(line).unwrap_or_else(|| line.to_string());
+    line.starts_with("**Search**:") || line.starts_with("**Content**:")
+}
+
+// Removes the <pre> and </pre> the instructions ask models to wrap a plan in: one opening
+// tag at the start of the first line with text and one closing tag at the end of the
+// last. The closing tag only counts on a line of its own or after an opening tag, since a
+// diff or code may well end in "</pre>". Tags anywhere else belong to the plan's code.
+// Line numbers are unchanged.
+pub fn strip_pre_wrapper(input: &str) -> String {
+    let mut lines: Vec<&str> = input.split_inclusive('\n').collect();
+    let mut opened = false;
+    if let Some(first) = lines.iter_mut().find(|line| !line.trim().is_empty()) {
+        let text = first.trim_start();
+        if text
+            .get(..5)
+            .is_some_and(|tag| tag.eq_ignore_ascii_case("<pre>"))
+        {
+            *first = &text[5..];
+            opened = true;
+        }
+    }
+    let mut stripped = String::with_capacity(input.len());
+    let last = lines.iter().rposition(|line| !line.trim().is_empty());
+    for (index, line) in lines.into_iter().enumerate() {
+        let text = line.trim_end();
+        let body = text.len().saturating_sub(6);
+        let is_close = text
+            .get(body..)
+            .is_some_and(|tag| tag.eq_ignore_ascii_case("</pre>"))
+            && (opened || text[..body].trim().is_empty());
+        if Some(index) == last && is_close {
+            stripped.push_str(&text[..body]);
+            stripped.push_str(&line[text.len()..]);
+        } else {
+            stripped.push_str(line);
+        }
+    }
+    stripped
+}
+
+// Every error found in a plan, with the warnings that came with them. Apply and preview
+// fail with it; validate reports the diagnostics.
+#[derive(Debug)]
+pub struct InvalidPlan {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl fmt::Display for InvalidPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let errors: Vec<String> = self
+            .diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+            .map(|d| format!("Line {}:{}: {}", d.line, d.column, d.message))
+            .collect();
+        write!(f, "{}", errors.join("\n"))
+    }
+}
+
+impl std::error::Error for InvalidPlan {}
+
+// 1-based, end-exclusive column span of `needle` in `line`; the whole line (minus its
+// indentation) when the needle is empty or not there
+fn span(line: &str, needle: &str) -> (usize, usize) {
+    let (start, len) = match line.find(needle).filter(|_| !needle.is_empty()) {
+        Some(start) => (start, needle.len()),
+        None => {
+            let start = line.len() - line.trim_start().len();
+            (start, line.trim().len())
+        }
+    };
+    let column = line[..start].chars().count() + 1;
+    (column, column + line[start..start + len].chars().count())
+}
+
+// The file block being read; problems with the block as a whole are reported against
+// its ### File line
+struct OpenFile {
+    path: String,
+    line: usize,
+    columns: (usize, usize),
+    action: Option<Action>,
+    // Set for the no-op action "none" and for an action that could not be read
+    skip: bool,
+    mode: Option<u32>,
+    hash: Option<String>,
+    target: Option<(String, usize, (usize, usize))>,
+    // Each change with the line it starts on
+    changes: Vec<(usize, Change)>,
+    in_change: bool,
+}
+
+// The change block being read
+#[derive(Default)]
+struct OpenChange {
+    line: usize,
+    description: String,
+    search: Option<String>,
+    content: String,
+    occurrence: Option<Occurrence>,
+}
+
+impl OpenChange {
+    fn is_empty(&self) -> bool {
+        self.description.is_empty() && self.search.is_none() && self.content.is_empty()
+    }
+
+    fn into_change(self) -> Change {
+        Change {
+            index: 0,
+            description: self.description.trim().to_string(),
+            search: self.search,
+            content: self.content,
+            occurrence: self.occurrence,
+            line_hint: None,
+        }
+    }
+}
+
+fn finish_change(file: Option<&mut OpenFile>, change: &mut OpenChange) {
+    let change = std::mem::take(change);
+    if let Some(file) = file.filter(|_| !change.is_empty()) {
+        file.changes.push((change.line, change.into_change()));
+    }
+}
+
+fn finish_file(
+    file: OpenFile,
+    file_changes: &mut Vec<FileChange>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    if file.skip {
+        return;
+    }
+    let Some(action) = file.action else {
+        diagnostics.push(Diagnostic::new(
+            Severity::Error,
+            "missing-action",
+            file.line,
+            file.columns,
+            format!("Missing action for file: {}", file.path),
+        ));
+        return;
+    };
+    let (target_value, target_line, target_columns) = match file.target {
+        Some((value, line, columns)) => (Some(value), line, columns),
+        None => (None, file.line, file.columns),
+    };
+    let target = match parse_target(&file.path, &Some(action.clone()), target_value) {
+        Ok(target) => target,
+        Err(err) => {
+            let (code, line, columns) = match action {
+                Action::Rename | Action::Copy => ("missing-target", file.line, file.columns),
+                _ => ("unexpected-target", target_line, target_columns),
+            };
+            diagnostics.push(Diagnostic::new(
+                Severity::Error,
+                code,
+                line,
+                columns,
+                err.to_string(),
+            ));
+            return;
+        }
+    };
+    let needs_search = matches!(action, Action::Modify | Action::Rename);
+    for (line, change) in &file.changes {
+        let problem = match (&action, &change.search) {
+            (_, None) if needs_search => Some((
+                Severity::Error,
+                "missing-search",
+                "Change has no Search block; modify changes need one",
+            )),
+            (Action::Create | Action::Rewrite, Some(_)) => Some((
+                Severity::Warning,
+                "search-ignored",
+                "Search is ignored for create and rewrite; Content is the whole file",
+            )),
+            (Action::Delete | Action::CreateDir | Action::DeleteDir | Action::Copy, _) => Some((
+                Severity::Warning,
+                "change-ignored",
+                "Changes are ignored for this action",
+            )),
+            _ => None,
+        };
+        if let Some((severity, code, message)) = problem {
+            diagnostics.push(Diagnostic::new(
+                severity,
+                code,
+                *line,
+                (1, 1),
+                message.to_string(),
+            ));
+        }
+    }
+    file_changes.push(FileChange {
+        index: file_changes.len(),
+        path: PathBuf::from(file.path),
+        action,
+        changes: file.changes.into_iter().map(|(_, change)| change).collect(),
+        mode: file.mode,
+        hash: file.hash,
+        target,
+        final_newline: None,
+        excluded: Vec::new(),
+    });
+}
+
+pub fn parse_change_protocol(xml_protocol: &str) -> Result<(Vec<FileChange>, Vec<Diagnostic>)> {
     let mut file_changes: Vec<FileChange> = Vec::new();
-    let mut current_file_path: Option<String> = None;
-    let mut current_action: Option<Action> = None;
-    let mut current_changes: Vec<Change> = Vec::new();
-
-    // Variables for the current change block
-    let mut current_description = String::new();
-    let mut current_search: Option<String> = None;
-    let mut current_content = String::new();
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    let mut file: Option<OpenFile> = None;
+    let mut change = OpenChange::default();
     let mut reading_field: Option<String> = None; // "description", "search", "content"
-    let mut in_code_block = false;
+    let mut open_fence: Option<(Fence, usize)> = None;
     let mut code_field: Option<String> = None;
     let mut code_lines: Vec<String> = Vec::new();
 
-    // Skip file blocks with a no-op action ("none")
-    let mut skip_current_file = false;
-
-    // Remove surrounding <pre> tags if present
-    let stripped = xml_protocol.replace("<pre>", "").replace("</pre>", "");
+    let stripped = strip_pre_wrapper(xml_protocol);
     let lines: Vec<&str> = stripped.lines().collect();
     log::debug!("Stripped {}", stripped);
 
-    for line in lines {
-        let line = line.trim_end();
+    for (index, raw_line) in lines.into_iter().enumerate() {
+        let line_no = index + 1;
+        let line = raw_line.trim_end();
+        // Inside a code block everything up to the closing fence is code, including blank
+        // lines, "---" and lines that look like headers or field markers
+        if let Some((fence, _)) = &open_fence {
+            // Code is kept byte for byte, trailing whitespace included
+            if !fence.closes(line) {
+                code_lines.push(fence.strip_indent(raw_line).to_string());
+                continue;
+            }
+            open_fence = None;
+            let code = code_lines.join("\n");
+            if let Some(field) = code_field.clone() {
+                if field == "search" {
+                    change.search = Some(code);
+                } else if field == "content" {
+                    change.content = code;
+                }
+            }
+            code_field = None;
+            reading_field = None;
+            continue;
+        }
         // Skip empty lines and horizontal rules
         if line.trim().is_empty() || line.trim() == "---" {
             continue;
         }
 
-        // Detect action header and support "none"
-        if line.starts_with("### Action ") {
-            let action_str = line.strip_prefix("### Action ").unwrap().trim();
-            if action_str == "none" {
-                skip_current_file = true;
-                current_action = None;
-            } else {
-                skip_current_file = false;
-                current_action = Some(match action_str {
-                    "modify" => Action::Modify,
-                    "rewrite" => Action::Rewrite,
-                    "create" => Action::Create,
-                    "delete" => Action::Delete,
-                    other => return Err(anyhow!("Unknown action: {}", other)),
-                });
+        let normalized = normalize_line(line);
+        match &normalized {
+            Some(fixed) if fixed == line.trim() => diagnostics.push(Diagnostic::new(
+                Severity::Warning,
+                "normalized-indentation",
+                line_no,
+                span(line, ""),
+                format!("Removed indentation before `{}`", fixed),
+            )),
+            Some(fixed) => diagnostics.push(Diagnostic::new(
+                Severity::Warning,
+                "normalized-syntax",
+                line_no,
+                span(line, ""),
+                format!("Read `{}` as `{}`", line.trim(), fixed),
+            )),
+            None => {}
+        }
+        let canonical = normalized.as_deref().unwrap_or(line);
+
+        // Headers other than ### File describe the open file block
+        let header = HEADERS
+            .iter()
+            .filter(|header| **header != "File")
+            .find_map(|header| {
+                canonical
+                    .strip_prefix(&format!("### {} ", header))
+                    .map(|value| (*header, value.trim()))
+            });
+        if let Some((header, value)) = header {
+            // Before the first file it is a heading of the plan's prose, e.g. "### To do"
+            let Some(file) = file.as_mut() else {
+                diagnostics.push(Diagnostic::new(
+                    Severity::Warning,
+                    "header-outside-file",
+                    line_no,
+                    span(line, ""),
+                    format!(
+                        "`{}` comes before any ### File header and is read as prose",
+                        line.trim()
+                    ),
+                ));
+                continue;
+            };
+            match header {
+                // Detect action header and support "none"
+                "Action" => match parse_action(value) {
+                    Ok(action) => {
+                        file.skip = action.is_none();
+                        file.action = action;
+                    }
+                    Err(err) => {
+                        file.skip = true;
+                        diagnostics.push(Diagnostic::new(
+                            Severity::Error,
+                            "unknown-action",
+                            line_no,
+                            span(line, value),
+                            err.to_string(),
+                        ));
+                    }
+                },
+                // Optional permissions, e.g. "### Mode executable" or "### Mode 644"
+                "Mode" => match parse_mode(value) {
+                    Ok(mode) => file.mode = Some(mode),
+                    Err(err) => diagnostics.push(Diagnostic::new(
+                        Severity::Error,
+                        "invalid-mode",
+                        line_no,
+                        span(line, value),
+                        err.to_string(),
+                    )),
+                },
+                // Optional content hash of the file as it was shown to the model
+                "Hash" if value.is_empty() => {}
+                "Hash" => match parse_hash(value) {
+                    Ok(hash) => file.hash = Some(hash),
+                    Err(err) => diagnostics.push(Diagnostic::new(
+                        Severity::Error,
+                        "invalid-hash",
+                        line_no,
+                        span(line, value),
+                        err.to_string(),
+                    )),
+                },
+                // Destination of a rename or copy, e.g. "### To src/new_name.rs"
+                _ => file.target = Some((value.to_string(), line_no, span(line, value))),
             }
             continue;
         }
 
         // Detect file block header
-        if line.starts_with("### File ") {
-            // Finalize previous change block if exists
-            if !current_description.is_empty()
-                || current_search.is_some()
-                || !current_content.is_empty()
-            {
-                current_changes.push(Change {
-                    description: current_description.trim().to_string(),
-                    search: current_search.clone(),
-                    content: current_content.trim().to_string(),
-                });
-                current_description.clear();
-                current_search = None;
-                current_content.clear();
-                reading_field = None;
-            }
-            // Finalize previous file block if exists, only if not skipped
-            if let Some(file_path) = current_file_path.take() {
-                if !skip_current_file {
-                    if current_action.is_none() {
-                        return Err(anyhow!("Missing action for file: {}", file_path));
-                    }
-                    file_changes.push(FileChange {
-                        path: PathBuf::from(file_path),
-                        action: current_action.clone().unwrap(),
-                        changes: current_changes.clone(),
-                    });
-                }
-                // Reset for next file
-                current_changes.clear();
-                current_action = None;
-                skip_current_file = false;
+        if let Some(path) = canonical.strip_prefix("### File ") {
+            finish_change(file.as_mut(), &mut change);
+            reading_field = None;
+            if let Some(previous) = file.take() {
+                finish_file(previous, &mut file_changes, &mut diagnostics);
             }
-            // Set new file path
-            let file_path = line.strip_prefix("### File ").unwrap().trim().to_string();
-            current_file_path = Some(file_path);
+            let path = path.trim().to_string();
+            file = Some(OpenFile {
+                columns: span(line, ""),
+                line: line_no,
+                path,
+                action: None,
+                skip: false,
+                mode: None,
+                hash: None,
+                target: None,
+                changes: Vec::new(),
+                in_change: false,
+            });
             continue;
         }
 
         // Detect change block header
-        if line.starts_with("#### Change") {
-            // Finalize previous change block if exists
-            if !current_description.is_empty()
-                || current_search.is_some()
-                || !current_content.is_empty()
-            {
-                current_changes.push(Change {
-                    description: current_description.trim().to_string(),
-                    search: current_search.clone(),
-                    content: current_content.trim().to_string(),
-                });
-                current_description.clear();
-                current_search = None;
-                current_content.clear();
-                reading_field = None;
+        if canonical.starts_with("#### Change") {
+            finish_change(file.as_mut(), &mut change);
+            reading_field = None;
+            change.line = line_no;
+            if let Some(file) = file.as_mut() {
+                file.in_change = true;
             }
             continue;
         }
 
         // Detect field markers
-        if line.starts_with("**Description**:") {
-            reading_field = Some("description".to_string());
-            let desc = line.strip_prefix("**Description**:").unwrap().trim();
-            current_description.push_str(desc);
-            continue;
-        }
-        if line.starts_with("**Search**:") {
-            reading_field = Some("search".to_string());
-            continue;
-        }
-        if line.starts_with("**Content**:") {
-            reading_field = Some("content".to_string());
+        let field = FIELDS.iter().find_map(|field| {
+            canonical
+                .strip_prefix(&format!("**{}**:", field))
+                .map(|value| (*field, value.trim()))
+        });
+        if let Some((field, value)) = field {
+            match file.as_mut() {
+                None => {
+                    diagnostics.push(Diagnostic::new(
+                        Severity::Error,
+                        "field-outside-file",
+                        line_no,
+                        span(line, ""),
+                        format!("**{}** appears before any ### File header", field),
+                    ));
+                }
+                Some(file) if !file.in_change => {
+                    diagnostics.push(Diagnostic::new(
+                        Severity::Warning,
+                        "missing-change-header",
+                        line_no,
+                        span(line, ""),
+                        format!("**{}** appears outside a #### Change block", field),
+                    ));
+                    file.in_change = true;
+                    change.line = line_no;
+                }
+                Some(_) => {}
+            }
+            match field {
+                "Description" => {
+                    reading_field = Some("description".to_string());
+                    change.description.push_str(value);
+                }
+                "Search" => reading_field = Some("search".to_string()),
+                "Content" => reading_field = Some("content".to_string()),
+                _ => {
+                    match parse_occurrence(value) {
+                        Ok(occurrence) => change.occurrence = Some(occurrence),
+                        Err(err) => diagnostics.push(Diagnostic::new(
+                            Severity::Error,
+                            "invalid-occurrence",
+                            line_no,
+                            span(line, value),
+                            err.to_string(),
+                        )),
+                    }
+                    reading_field = None;
+                }
+            }
             continue;
         }
 
-        // Handle code block markers
-        if line.starts_with("```") {
-            if !in_code_block {
-                // Start of a code block
-                in_code_block = true;
+        // Start of a code block. Only Search and Content take code; any other fence (such
+        // as one wrapping the whole plan) is layout and skipped
+        if let Some(fence) = Fence::open(line) {
+            if matches!(reading_field.as_deref(), Some("search") | Some("content")) {
+                open_fence = Some((fence, line_no));
                 code_field = reading_field.clone();
                 code_lines.clear();
-            } else {
-                // End of a code block
-                in_code_block = false;
-                let code = code_lines.join("\n");
-                if let Some(field) = code_field.clone() {
-                    if field == "search" {
-                        current_search = Some(code);
-                    } else if field == "content" {
-                        current_content = code;
-                    }
-                }
-                code_field = None;
-                reading_field = None;
+            } else if file.is_none() && file_changes.is_empty() {
+                diagnostics.push(Diagnostic::new(
+                    Severity::Warning,
+                    "ignored-fence",
+                    line_no,
+                    span(line, ""),
+                    "Ignored the code fence around the plan; wrap plans in <pre> instead"
+                        .to_string(),
+                ));
             }
             continue;
         }
 
-        // Accumulate lines within a code block
-        if in_code_block {
-            code_lines.push(line.to_string());
+        // Search and Content are only read from code fences
+        if matches!(reading_field.as_deref(), Some("search") | Some("content")) {
+            diagnostics.push(Diagnostic::new(
+                Severity::Warning,
+                "text-outside-fence",
+                line_no,
+                span(line, ""),
+                format!(
+                    "Ignored `{}`: {} must be inside a code fence",
+                    line.trim(),
+                    if reading_field.as_deref() == Some("search") {
+                        "Search"
+                    } else {
+                        "Content"
+                    }
+                ),
+            ));
             continue;
         }
 
         // Append to description if reading description
         if let Some(field) = reading_field.clone() {
             if field == "description" {
-                if !current_description.is_empty() {
-                    current_description.push_str("\n");
+                if !change.description.is_empty() {
+                    change.description.push('\n');
                 }
-                current_description.push_str(line);
+                change.description.push_str(line);
             }
         }
     }
 
-    // Finalize any pending change block
-    if !current_description.is_empty() || current_search.is_some() || !current_content.is_empty() {
-        current_changes.push(Change {
-            description: current_description.trim().to_string(),
-            search: current_search.clone(),
-            content: current_content.trim().to_string(),
-        });
+    if let Some((_, fence_line)) = open_fence {
+        let line = stripped.lines().nth(fence_line - 1).unwrap_or_default();
+        diagnostics.push(Diagnostic::new(
+            Severity::Error,
+            "unclosed-fence",
+            fence_line,
+            span(line, ""),
+            "Code fence is never closed".to_string(),
+        ));
     }
 
-    // Finalize last file block if exists (skip if marked "none")
-    if let Some(file_path) = current_file_path.take() {
-        if !skip_current_file {
-            if current_action.is_none() {
-                return Err(anyhow!("Missing action for file: {}", file_path));
-            }
-            file_changes.push(FileChange {
-                path: PathBuf::from(file_path),
-                action: current_action.clone().unwrap(),
-                changes: current_changes.clone(),
-            });
+    // Finalize the last change and file block
+    finish_change(file.as_mut(), &mut change);
+    if let Some(file) = file.take() {
+        finish_file(file, &mut file_changes, &mut diagnostics);
+    }
+
+    if diagnostics.iter().any(|d| d.severity == Severity::Error) {
+        return Err(InvalidPlan { diagnostics }.into());
+    }
+    Ok((file_changes, diagnostics))
+}
+
+// A code fence as CommonMark defines it: three or more backticks or tildes, closed only
+// by a run of the same character at least as long, so a ```` block can hold ``` samples.
+// The opening fence may be indented; that much indentation is stripped from its lines.
+pub struct Fence {
+    marker: char,
+    len: usize,
+    indent: usize,
+}
+
+impl Fence {
+    pub fn open(line: &str) -> Option<Fence> {
+        let trimmed = line.trim_start_matches([' ', '\t']);
+        let marker = trimmed.chars().next().filter(|c| *c == '`' || *c == '~')?;
+        let len = trimmed.chars().take_while(|c| *c == marker).count();
+        // The info string of a backtick fence cannot itself contain backticks
+        if len < 3 || (marker == '`' && trimmed[len..].contains('`')) {
+            return None;
         }
+        Some(Fence {
+            marker,
+            len,
+            indent: line.len() - trimmed.len(),
+        })
     }
 
-    Ok(file_changes)
+    pub fn closes(&self, line: &str) -> bool {
+        let trimmed = line.trim();
+        let len = trimmed.chars().take_while(|c| *c == self.marker).count();
+        len >= self.len && len == trimmed.len()
+    }
+
+    fn strip_indent<'a>(&self, line: &'a str) -> &'a str {
+        let indent = line.len() - line.trim_start_matches([' ', '\t']).len();
+        &line[indent.min(self.indent)..]
+    }
+}
+
+// None for the no-op action "none"
+pub fn parse_action(value: &str) -> Result<Option<Action>> {
+    Ok(Some(match value {
+        "none" => return Ok(None),
+        "modify" => Action::Modify,
+        "rewrite" => Action::Rewrite,
+        "create" => Action::Create,
+        "delete" => Action::Delete,
+        "rename" | "move" => Action::Rename,
+        "create-dir" => Action::CreateDir,
+        "delete-dir" => Action::DeleteDir,
+        "copy" => Action::Copy,
+        other => return Err(anyhow!("Unknown action: {}", other)),
+    }))
+}
+
+// Rename and copy need a `### To` line; any other action must not have one
+pub fn parse_target(
+    file_path: &str,
+    action: &Option<Action>,
+    target: Option<String>,
+) -> Result<Option<PathBuf>> {
+    let needs_target = matches!(action, Some(Action::Rename) | Some(Action::Copy));
+    match (needs_target, target) {
+        (true, Some(target)) => Ok(Some(PathBuf::from(target))),
+        (true, None) => Err(anyhow!("Missing target path (### To) for: {}", file_path)),
+        (false, Some(_)) => Err(anyhow!(
+            "Target path given for an action that does not take one: {}",
+            file_path
+        )),
+        (false, None) => Ok(None),
+    }
+}
+
+pub fn parse_occurrence(value: &str) -> Result<Occurrence> {
+    if value.eq_ignore_ascii_case("all") {
+        return Ok(Occurrence::All);
+    }
+    match value.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(Occurrence::Nth(n)),
+        _ => Err(anyhow!(
+            "Invalid occurrence: {} (expected a match number starting at 1, or \"all\")",
+            value
+        )),
+    }
+}
+
+// A content hash as written in a plan: hex, at least MIN_HASH_LEN digits
+pub fn parse_hash(value: &str) -> Result<String> {
+    let hash = value.trim().to_lowercase();
+    if hash.len() < MIN_HASH_LEN || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(anyhow!(
+            "Invalid hash: {} (expected at least {} hex digits)",
+            value,
+            MIN_HASH_LEN
+        ));
+    }
+    Ok(hash)
+}
+
+pub fn parse_mode(value: &str) -> Result<u32> {
+    if value == "executable" {
+        return Ok(0o755);
+    }
+    u32::from_str_radix(value, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| {
+            anyhow!(
+                "Invalid mode: {} (expected \"executable\" or octal bits like 755)",
+                value
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codes(diagnostics: &[Diagnostic]) -> Vec<(&'static str, usize)> {
+        diagnostics.iter().map(|d| (d.code, d.line)).collect()
+    }
+
+    #[test]
+    fn recovers_from_near_miss_headers() {
+        let input = "  ### File: `src/a.ts`\n### Action: Edit\n#### change\n**Search:**\n```\nold\n```\n**Content:**\n```\nnew\n```\n";
+        let (files, diagnostics) = parse_change_protocol(input).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, PathBuf::from("src/a.ts"));
+        assert_eq!(files[0].action, Action::Modify);
+        assert_eq!(files[0].changes[0].search.as_deref(), Some("old"));
+        assert_eq!(files[0].changes[0].content, "new");
+        assert!(diagnostics.iter().all(|d| d.severity == Severity::Warning));
+        assert_eq!(
+            codes(&diagnostics),
+            vec![
+                ("normalized-syntax", 1),
+                ("normalized-syntax", 2),
+                ("normalized-syntax", 3),
+                ("normalized-syntax", 4),
+                ("normalized-syntax", 8)
+            ]
+        );
+    }
+
+    #[test]
+    fn warns_about_ignored_fences_and_text() {
+        let input = "```markdown\n    ### File a.txt\n### Action create\n#### Change\n**Content**:\nloose text\n```\nhi\n```\n```\n";
+        let (files, diagnostics) = parse_change_protocol(input).unwrap();
+        assert_eq!(files[0].changes[0].content, "hi");
+        assert_eq!(
+            codes(&diagnostics),
+            vec![
+                ("ignored-fence", 1),
+                ("normalized-indentation", 2),
+                ("text-outside-fence", 6)
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_recover() {
+        assert!(parse_change_protocol("### File a.txt\n### Action explode\n").is_err());
+        assert!(
+            parse_change_protocol("### File a.txt\n### Action modify\n### Hash abc\n").is_err()
+        );
+        assert!(
+            parse_change_protocol("### File a.txt\n### Action create\n### Mode 999\n").is_err()
+        );
+        assert!(parse_hash("0123ABCD").is_ok());
+        assert_eq!(parse_mode("executable").unwrap(), 0o755);
+    }
+
+    #[test]
+    fn strips_only_the_outer_pre_wrapper() {
+        assert_eq!(strip_pre_wrapper("\n<pre>\na\n</pre>\n"), "\n\na\n\n");
+        assert_eq!(strip_pre_wrapper("<pre>a</pre>"), "a");
+        assert_eq!(strip_pre_wrapper("x\n+</pre>\n"), "x\n+</pre>\n");
+        assert_eq!(strip_pre_wrapper("x\n  </pre>\n"), "x\n  \n");
+        assert_eq!(
+            strip_pre_wrapper("a <pre>b</pre> c\n"),
+            "a <pre>b</pre> c\n"
+        );
+        let input = "<pre>\n### File a.html\n### Action create\n#### Change\n**Content**:\n```\n<pre>\nx</pre>\n</pre>\n```\n</pre>\n";
+        let (files, _) = parse_change_protocol(input).unwrap();
+        assert_eq!(files[0].changes[0].content, "<pre>\nx</pre>\n</pre>");
+    }
 }
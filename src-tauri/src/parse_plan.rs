@@ -0,0 +1,204 @@
+use crate::change_types::{Diagnostic, FileChange, Plan, PlanSummary, Severity, ValidationReport};
+use crate::parse_change_protocol::{
+    is_file_header, parse_change_protocol, strip_pre_wrapper, InvalidPlan,
+};
+use crate::parse_search_replace::{is_search_marker, parse_search_replace};
+use crate::parse_unified_diff::parse_unified_diff;
+use crate::parse_xml_protocol::{is_file_tag, parse_xml_protocol};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlanFormat {
+    Markdown,
+    UnifiedDiff,
+    SearchReplace,
+    Xml,
+}
+
+// Markdown file headers and XML <file> elements win over everything else, so a plan whose
+// code creates a .patch file or contains conflict markers is never mistaken for another
+// dialect. Between the two, whichever comes first decides: code inside either one may
+// well contain the other.
+pub fn detect_format(input: &str) -> PlanFormat {
+    let lines: Vec<&str> = input.lines().map(str::trim_end).collect();
+    let markdown = lines.iter().position(|line| is_file_header(line));
+    let xml = lines.iter().position(|line| is_file_tag(line));
+    match (markdown, xml) {
+        (Some(markdown), Some(xml)) if xml < markdown => return PlanFormat::Xml,
+        (Some(_), _) => return PlanFormat::Markdown,
+        (None, Some(_)) => return PlanFormat::Xml,
+        (None, None) => {}
+    }
+    if lines.iter().any(|line| is_search_marker(line)) {
+        return PlanFormat::SearchReplace;
+    }
+    let git_header = lines.iter().any(|line| line.starts_with("diff --git "));
+    let file_header = lines
+        .windows(2)
+        .any(|pair| pair[0].starts_with("--- ") && pair[1].starts_with("+++ "));
+    let hunk = lines.iter().any(|line| line.starts_with("@@ "));
+    if git_header || (file_header && hunk) {
+        PlanFormat::UnifiedDiff
+    } else {
+        PlanFormat::Markdown
+    }
+}
+
+// Only the markdown and diff parsers are lenient, so they are the only ones that report
+// warnings
+pub fn parse_plan(input: &str) -> Result<Plan> {
+    let format = detect_format(input);
+    let (mut file_changes, warnings) = match format {
+        PlanFormat::Markdown => parse_change_protocol(input)?,
+        PlanFormat::UnifiedDiff => parse_unified_diff(input)?,
+        PlanFormat::SearchReplace => (parse_search_replace(input)?, Vec::new()),
+        PlanFormat::Xml => (parse_xml_protocol(input)?, Vec::new()),
+    };
+    assign_ids(&mut file_changes);
+    Ok(Plan {
+        format,
+        narrative: narrative(input, format),
+        file_changes,
+        warnings,
+    })
+}
+
+// Numbers file changes and their changes by position, which is what the ids a selective
+// apply refers to are built from; done once here so every dialect numbers alike
+pub fn assign_ids(file_changes: &mut [FileChange]) {
+    for (index, file) in file_changes.iter_mut().enumerate() {
+        file.index = index;
+        for (change_index, change) in file.changes.iter_mut().enumerate() {
+            change.index = change_index;
+        }
+    }
+}
+
+pub fn summarize_plan(input: &str) -> Result<PlanSummary> {
+    Ok(parse_plan(input)?.summary())
+}
+
+// Index of the line the first file change starts on, or the line count if there is none
+fn body_start(lines: &[&str], format: PlanFormat) -> usize {
+    let first = match format {
+        PlanFormat::Markdown => lines.iter().position(|line| is_file_header(line)),
+        PlanFormat::Xml => lines.iter().position(|line| is_file_tag(line)),
+        PlanFormat::UnifiedDiff => (0..lines.len()).find(|&i| {
+            lines[i].starts_with("diff --git ")
+                || (lines[i].starts_with("--- ")
+                    && lines.get(i + 1).is_some_and(|l| l.starts_with("+++ ")))
+        }),
+        // The path line above the first block, past any fence between them
+        PlanFormat::SearchReplace => {
+            lines
+                .iter()
+                .position(|line| is_search_marker(line))
+                .map(|marker| {
+                    let mut start = marker;
+                    while start > 0 && is_layout(lines[start - 1]) {
+                        start -= 1;
+                    }
+                    start.saturating_sub(1)
+                })
+        }
+    };
+    first.unwrap_or(lines.len())
+}
+
+fn is_layout(line: &str) -> bool {
+    let line = line.trim();
+    line.is_empty() || line.starts_with("```") || line.starts_with("~~~")
+}
+
+// The prose before the first file change, without the protocol's own scaffolding: the
+// <pre> wrapper, the `# Plan` and `## Files` headings, and a fence or root element
+// opened around the changes
+fn narrative(input: &str, format: PlanFormat) -> String {
+    let stripped = strip_pre_wrapper(input);
+    let lines: Vec<&str> = stripped.lines().map(str::trim_end).collect();
+    let mut prose = &lines[..body_start(&lines, format)];
+    while let Some((last, rest)) = prose.split_last() {
+        let opens_element = last.trim_start().starts_with('<') && last.ends_with('>');
+        if !is_layout(last) && !opens_element {
+            break;
+        }
+        prose = rest;
+    }
+    prose
+        .iter()
+        .filter(|line| {
+            let heading = line.trim();
+            !heading.eq_ignore_ascii_case("# Plan") && !heading.eq_ignore_ascii_case("## Files")
+        })
+        .copied()
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+// Diagnostics for a failed parse. The other dialects stop at their first error, which is
+// reported against the start of the plan.
+pub fn plan_diagnostics(err: &anyhow::Error) -> Vec<Diagnostic> {
+    match err.downcast_ref::<InvalidPlan>() {
+        Some(invalid) => invalid.diagnostics.clone(),
+        None => vec![Diagnostic::new(
+            Severity::Error,
+            "parse-error",
+            1,
+            (1, 1),
+            format!("{:#}", err),
+        )],
+    }
+}
+
+// Parses without touching the disk so the editor can flag problems before apply
+pub fn validate_plan(input: &str) -> ValidationReport {
+    let format = detect_format(input);
+    match parse_plan(input) {
+        Ok(plan) => ValidationReport {
+            format,
+            valid: true,
+            files: plan.file_changes.len(),
+            diagnostics: plan.warnings,
+        },
+        Err(err) => ValidationReport {
+            format,
+            valid: false,
+            files: 0,
+            diagnostics: plan_diagnostics(&err),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headings_before_the_first_file_are_prose() {
+        let input = "# Plan\n\n### Mode of operation\nSmall steps.\n\n### To do\n- rename\n\n## Files\n\n### File a.txt\n### Action delete\n";
+        let plan = parse_plan(input).unwrap();
+        assert_eq!(plan.file_changes.len(), 1);
+        assert_eq!(
+            plan.narrative,
+            "### Mode of operation\nSmall steps.\n\n### To do\n- rename"
+        );
+        let codes: Vec<_> = plan.warnings.iter().map(|d| (d.code, d.line)).collect();
+        assert_eq!(
+            codes,
+            vec![("header-outside-file", 3), ("header-outside-file", 6)]
+        );
+        assert!(validate_plan(input).valid);
+    }
+
+    #[test]
+    fn assigns_ids_by_position() {
+        let input = "### File a.txt\n### Action modify\n#### Change\n**Search**:\n```\na\n```\n**Content**:\n```\nb\n```\n#### Change\n**Search**:\n```\nc\n```\n**Content**:\n```\nd\n```\n### File b.txt\n### Action delete\n";
+        let summary = summarize_plan(input).unwrap();
+        assert_eq!(summary.files[0].change_ids, vec!["0:0", "0:1"]);
+        assert_eq!(summary.files[1].id, "1");
+    }
+}
@@ -0,0 +1,180 @@
+use crate::change_types::{Action, Change, FileChange};
+use crate::parse_change_protocol::strip_pre_wrapper;
+use anyhow::{anyhow, Result};
+use std::path::{Path, PathBuf};
+
+// "<<<<<<< SEARCH", "=======", ">>>>>>> REPLACE"; models are not always exact about
+// the number of marker characters, so anywhere from 5 to 9 is accepted
+fn is_marker(line: &str, marker: char, word: &str) -> bool {
+    let line = line.trim();
+    let count = line.chars().take_while(|c| *c == marker).count();
+    (5..=9).contains(&count) && line[count..].trim() == word
+}
+
+pub fn is_search_marker(line: &str) -> bool {
+    is_marker(line, '<', "SEARCH")
+}
+
+fn is_divider(line: &str) -> bool {
+    is_marker(line, '=', "")
+}
+
+fn is_replace_marker(line: &str) -> bool {
+    is_marker(line, '>', "REPLACE")
+}
+
+// Any line the parser would read as part of a block's structure
+pub fn is_block_marker(line: &str) -> bool {
+    is_search_marker(line) || is_divider(line) || is_replace_marker(line)
+}
+
+fn is_fence(line: &str) -> bool {
+    let line = line.trim_start();
+    line.starts_with("```") || line.starts_with("~~~")
+}
+
+// The path line sits right above the block, possibly with a code fence in between and
+// decorated as `path`, **path** or path:
+fn path_before(lines: &[&str]) -> Option<String> {
+    let line = lines
+        .iter()
+        .rev()
+        .map(|line| line.trim())
+        .find(|line| !line.is_empty() && !is_fence(line))?;
+    if is_replace_marker(line) {
+        return None;
+    }
+    let path = line
+        .trim_matches(|c| c == '`' || c == '*')
+        .trim_end_matches(':')
+        .trim_matches(|c| c == '`' || c == '*');
+    if path.is_empty() || path.contains(char::is_whitespace) {
+        return None;
+    }
+    Some(path.to_string())
+}
+
+pub fn parse_search_replace(input: &str) -> Result<Vec<FileChange>> {
+    let stripped = strip_pre_wrapper(input);
+    let lines: Vec<&str> = stripped.lines().collect();
+    let mut file_changes: Vec<FileChange> = Vec::new();
+    let mut current_path: Option<String> = None;
+    let mut i = 0;
+    while i < lines.len() {
+        if !is_search_marker(lines[i]) {
+            i += 1;
+            continue;
+        }
+        // A block without its own path line continues the previous file
+        let path = match path_before(&lines[..i]).or_else(|| current_path.clone()) {
+            Some(path) => path,
+            None => {
+                return Err(anyhow!(
+                    "SEARCH block at line {} has no file path line before it",
+                    i + 1
+                ))
+            }
+        };
+        let start = i + 1;
+        let divider = (start..lines.len())
+            .find(|&j| is_divider(lines[j]))
+            .ok_or_else(|| anyhow!("SEARCH block for {} has no ======= divider", path))?;
+        let end = (divider + 1..lines.len())
+            .find(|&j| is_replace_marker(lines[j]))
+            .ok_or_else(|| anyhow!("SEARCH block for {} has no >>>>>>> REPLACE marker", path))?;
+        let search = lines[start..divider].join("\n");
+        let content = lines[divider + 1..end].join("\n");
+        push_block(&mut file_changes, &path, search, content);
+        current_path = Some(path);
+        i = end + 1;
+    }
+    if file_changes.is_empty() {
+        return Err(anyhow!("No SEARCH/REPLACE blocks found"));
+    }
+    Ok(file_changes)
+}
+
+// Consecutive blocks for the same file become one file change with several changes;
+// an empty SEARCH means the REPLACE section is a new file
+fn push_block(file_changes: &mut Vec<FileChange>, path: &str, search: String, content: String) {
+    let creates = search.trim().is_empty();
+    let change = Change {
+        index: 0,
+        description: String::new(),
+        search: (!creates).then_some(search),
+        content,
+        occurrence: None,
+        line_hint: None,
+    };
+    if let Some(last) = file_changes.last_mut() {
+        if !creates && last.path == Path::new(path) && matches!(last.action, Action::Modify) {
+            last.changes.push(change);
+            return;
+        }
+    }
+    file_changes.push(FileChange {
+        index: file_changes.len(),
+        path: PathBuf::from(path),
+        action: if creates {
+            Action::Create
+        } else {
+            Action::Modify
+        },
+        changes: vec![change],
+        mode: None,
+        hash: None,
+        target: None,
+        final_newline: None,
+        excluded: Vec::new(),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_for_one_file_become_one_change_set() {
+        let input = "src/a.py\n```python\n<<<<<<< SEARCH\none\n=======\n1\n>>>>>>> REPLACE\n```\n\n```python\n<<<<<<< SEARCH\ntwo\n=======\n2\n>>>>>>> REPLACE\n```\n\nsrc/b.py\n```\n<<<<<<< SEARCH\n=======\nnew\n>>>>>>> REPLACE\n```\n";
+        let files = parse_search_replace(input).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].path, PathBuf::from("src/a.py"));
+        assert_eq!(files[0].action, Action::Modify);
+        let searches: Vec<_> = files[0]
+            .changes
+            .iter()
+            .map(|change| (change.search.as_deref(), change.content.as_str()))
+            .collect();
+        assert_eq!(searches, vec![(Some("one"), "1"), (Some("two"), "2")]);
+        assert_eq!(files[1].action, Action::Create);
+        assert_eq!(files[1].changes[0].content, "new");
+    }
+
+    #[test]
+    fn reads_decorated_path_lines() {
+        for line in ["`src/a.py`", "**src/a.py**", "src/a.py:", "**`src/a.py`**:"] {
+            let input = format!(
+                "{}\n```\n<<<<<<< SEARCH\nx\n=======\ny\n>>>>>>> REPLACE\n```\n",
+                line
+            );
+            let files = parse_search_replace(&input).unwrap();
+            assert_eq!(files[0].path, PathBuf::from("src/a.py"), "{}", line);
+        }
+        // Prose is not a path
+        let input = "Change this file\n<<<<<<< SEARCH\nx\n=======\ny\n>>>>>>> REPLACE\n";
+        assert!(parse_search_replace(input).is_err());
+    }
+
+    #[test]
+    fn refuses_incomplete_blocks() {
+        let err = parse_search_replace("a.py\n<<<<<<< SEARCH\nx\n>>>>>>> REPLACE\n")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "SEARCH block for a.py has no ======= divider");
+        let err = parse_search_replace("a.py\n<<<<<<< SEARCH\nx\n=======\ny\n")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("REPLACE"), "{}", err);
+        assert!(parse_search_replace("just text\n").is_err());
+    }
+}
@@ -0,0 +1,538 @@
+use crate::change_types::{Action, Change, Diagnostic, FileChange, FinalNewline, Severity};
+use crate::parse_change_protocol::strip_pre_wrapper;
+use anyhow::{anyhow, Result};
+use std::path::PathBuf;
+
+// One file section of a unified diff or git patch
+#[derive(Default)]
+struct FilePatch {
+    old_path: Option<String>,
+    new_path: Option<String>,
+    new_file: bool,
+    deleted: bool,
+    rename: Option<(String, String)>,
+    copy: Option<(String, String)>,
+    mode: Option<u32>,
+    hunks: Vec<Change>,
+}
+
+const DEV_NULL: &str = "/dev/null";
+
+// Warnings report hunks whose line counts had to be ignored
+pub fn parse_unified_diff(input: &str) -> Result<(Vec<FileChange>, Vec<Diagnostic>)> {
+    let stripped = strip_pre_wrapper(input);
+    let lines: Vec<&str> = stripped
+        .lines()
+        .map(|line| line.strip_suffix('\r').unwrap_or(line))
+        .collect();
+    let mut patches: Vec<FilePatch> = Vec::new();
+    let mut warnings = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        i += 1;
+
+        if let Some(rest) = line.strip_prefix("diff --git ") {
+            let (old_path, new_path) = split_git_header(rest);
+            patches.push(FilePatch {
+                old_path,
+                new_path,
+                ..Default::default()
+            });
+            continue;
+        }
+
+        // A plain `diff -u` file starts at its "---"/"+++" pair; in a git patch the pair
+        // belongs to the section opened by "diff --git"
+        if line.starts_with("--- ") && lines.get(i).is_some_and(|l| l.starts_with("+++ ")) {
+            let starts_file = patches
+                .last()
+                .is_none_or(|p| !p.hunks.is_empty() || p.rename.is_some() || p.copy.is_some());
+            if starts_file {
+                patches.push(FilePatch::default());
+            }
+            let patch = patches.last_mut().unwrap();
+            patch.old_path = Some(parse_path(&line[4..]));
+            patch.new_path = Some(parse_path(&lines[i][4..]));
+            i += 1;
+            continue;
+        }
+
+        if line.starts_with("@@ ") {
+            let patch = patches
+                .last_mut()
+                .ok_or_else(|| anyhow!("Hunk without a file header: {}", line))?;
+            let (hunk, consumed, warning) = parse_hunk(line, &lines[i..])?;
+            if let Some(message) = warning {
+                warnings.push(Diagnostic::new(
+                    Severity::Warning,
+                    "hunk-line-counts",
+                    i,
+                    (1, line.chars().count() + 1),
+                    message,
+                ));
+            }
+            patch.hunks.push(hunk);
+            i += consumed;
+            continue;
+        }
+
+        // Extended git headers; anything else between files (commit message, diffstat,
+        // "index" lines, code fences) carries nothing to apply
+        let Some(patch) = patches.last_mut() else {
+            continue;
+        };
+        if let Some(mode) = line.strip_prefix("new file mode ") {
+            patch.new_file = true;
+            patch.mode = Some(parse_git_mode(mode)?);
+        } else if let Some(mode) = line.strip_prefix("deleted file mode ") {
+            parse_git_mode(mode)?;
+            patch.deleted = true;
+        } else if let Some(mode) = line.strip_prefix("new mode ") {
+            patch.mode = Some(parse_git_mode(mode)?);
+        } else if let Some(mode) = line.strip_prefix("old mode ") {
+            parse_git_mode(mode)?;
+        } else if let Some(from) = line.strip_prefix("rename from ") {
+            patch.rename = Some((unquote(from), String::new()));
+        } else if let Some(to) = line.strip_prefix("rename to ") {
+            let from = patch
+                .rename
+                .take()
+                .map(|(from, _)| from)
+                .unwrap_or_default();
+            patch.rename = Some((from, unquote(to)));
+        } else if let Some(from) = line.strip_prefix("copy from ") {
+            patch.copy = Some((unquote(from), String::new()));
+        } else if let Some(to) = line.strip_prefix("copy to ") {
+            let from = patch.copy.take().map(|(from, _)| from).unwrap_or_default();
+            patch.copy = Some((from, unquote(to)));
+        } else if line.starts_with("GIT binary patch") || line.starts_with("Binary files ") {
+            return Err(anyhow!(
+                "Binary patches are not supported: {}",
+                patch_path(patch).unwrap_or_default()
+            ));
+        }
+    }
+
+    if patches.is_empty() {
+        return Err(anyhow!("No file sections found in diff"));
+    }
+    let mut file_changes = Vec::new();
+    for patch in patches {
+        push_file_changes(patch, &mut file_changes)?;
+    }
+    Ok((file_changes, warnings))
+}
+
+// Turns one patch section into the actions the apply pipeline understands
+fn push_file_changes(patch: FilePatch, file_changes: &mut Vec<FileChange>) -> Result<()> {
+    let path = patch_path(&patch).ok_or_else(|| anyhow!("Diff section without a file path"))?;
+    let mut push = |path: &str, action: Action, changes: Vec<Change>, target: Option<String>| {
+        // A diff says exactly how the files it writes end ("\ No newline at end of file")
+        let final_newline = matches!(action, Action::Modify | Action::Create | Action::Rename)
+            .then_some(FinalNewline::Preserve);
+        file_changes.push(FileChange {
+            index: file_changes.len(),
+            path: PathBuf::from(path),
+            action,
+            changes,
+            mode: patch.mode,
+            hash: None,
+            target: target.map(PathBuf::from),
+            final_newline,
+            excluded: Vec::new(),
+        });
+    };
+    let is_new = patch.new_file || patch.old_path.as_deref() == Some(DEV_NULL);
+    let is_deleted = patch.deleted || patch.new_path.as_deref() == Some(DEV_NULL);
+
+    if is_new {
+        let content: String = patch.hunks.iter().map(|h| h.content.clone()).collect();
+        let change = Change {
+            index: 0,
+            description: "New file from diff".to_string(),
+            search: None,
+            content,
+            occurrence: None,
+            line_hint: None,
+        };
+        push(&path, Action::Create, vec![change], None);
+    } else if is_deleted {
+        push(&path, Action::Delete, Vec::new(), None);
+    } else if let Some((from, to)) = patch.rename.clone() {
+        push(&from, Action::Rename, patch.hunks, Some(to));
+    } else if let Some((from, to)) = patch.copy.clone() {
+        push(&from, Action::Copy, Vec::new(), Some(to.clone()));
+        if !patch.hunks.is_empty() || patch.mode.is_some() {
+            push(&to, Action::Modify, patch.hunks, None);
+        }
+    } else {
+        push(&path, Action::Modify, patch.hunks, None);
+    }
+    Ok(())
+}
+
+// The path a section is about: the new side unless the file is being deleted
+fn patch_path(patch: &FilePatch) -> Option<String> {
+    let new_path = patch.new_path.as_deref().filter(|p| *p != DEV_NULL);
+    let old_path = patch.old_path.as_deref().filter(|p| *p != DEV_NULL);
+    let path = if patch.deleted {
+        old_path
+    } else {
+        new_path.or(old_path)
+    }?;
+    Some(strip_side_prefix(path).to_string())
+}
+
+fn strip_side_prefix(path: &str) -> &str {
+    path.strip_prefix("a/")
+        .or_else(|| path.strip_prefix("b/"))
+        .unwrap_or(path)
+}
+
+// "a/src/x.rs b/src/x.rs"; the paths may contain spaces, so prefer the split where both
+// sides name the same file
+fn split_git_header(rest: &str) -> (Option<String>, Option<String>) {
+    if rest.starts_with('"') {
+        let mut parts = rest.splitn(2, "\" ");
+        let old = parts.next().map(|p| unquote(&format!("{}\"", p)));
+        let new = parts.next().map(unquote);
+        return (old, new);
+    }
+    if rest.len() > 5 && rest.len() % 2 == 1 {
+        let half = (rest.len() - 1) / 2;
+        if rest.is_char_boundary(half) {
+            let (old, new) = (&rest[..half], &rest[half + 1..]);
+            if old.get(2..) == new.get(2..) {
+                return (Some(old.to_string()), Some(new.to_string()));
+            }
+        }
+    }
+    match rest.find(" b/") {
+        Some(split) => (
+            Some(rest[..split].to_string()),
+            Some(rest[split + 1..].to_string()),
+        ),
+        None => (None, None),
+    }
+}
+
+// "--- a/src/x.rs\t2024-01-01 10:00:00" -> "a/src/x.rs"
+fn parse_path(value: &str) -> String {
+    let value = value.split('\t').next().unwrap_or(value).trim_end();
+    unquote(value)
+}
+
+// Git quotes paths with unusual characters C-style
+fn unquote(value: &str) -> String {
+    let Some(inner) = value
+        .strip_prefix('"')
+        .and_then(|rest| rest.strip_suffix('"'))
+    else {
+        return value.to_string();
+    };
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+fn parse_git_mode(value: &str) -> Result<u32> {
+    let mode = u32::from_str_radix(value.trim(), 8)
+        .map_err(|_| anyhow!("Invalid file mode in diff: {}", value))?;
+    match mode & 0o170000 {
+        0o100000 => Ok(mode & 0o7777),
+        _ => Err(anyhow!(
+            "Only regular files are supported in diffs, found mode {}",
+            value
+        )),
+    }
+}
+
+// "@@ -12,7 +12,8 @@ fn main()" -> (old start, old len, new start, new len)
+pub fn parse_hunk_header(header: &str) -> Result<(usize, usize, usize, usize)> {
+    let invalid = || anyhow!("Invalid hunk header: {}", header);
+    let mut parts = header
+        .strip_prefix("@@ ")
+        .and_then(|rest| rest.split(" @@").next())
+        .ok_or_else(invalid)?
+        .split_whitespace();
+    let mut range = |sign: char| -> Result<(usize, usize)> {
+        let part = parts
+            .next()
+            .and_then(|p| p.strip_prefix(sign))
+            .ok_or_else(invalid)?;
+        let (start, len) = part.split_once(',').unwrap_or((part, "1"));
+        Ok((
+            start.parse().map_err(|_| invalid())?,
+            len.parse().map_err(|_| invalid())?,
+        ))
+    };
+    let (old_start, old_len) = range('-')?;
+    let (new_start, new_len) = range('+')?;
+    Ok((old_start, old_len, new_start, new_len))
+}
+
+// Reads a hunk by its line counts, so lines that merely look like headers inside it are
+// never misread. Model-written diffs often get the counts wrong or leave them out; then
+// the hunk runs to the next hunk or file header instead and the returned warning says so.
+// Returns the change, how many body lines were consumed and the warning, if any.
+fn parse_hunk(header: &str, body: &[&str]) -> Result<(Change, usize, Option<String>)> {
+    let counts = parse_hunk_header(header).ok();
+    let counted = counts.and_then(|(_, old_len, _, new_len)| counted_len(body, old_len, new_len));
+    let (consumed, warning) = match counted {
+        Some(consumed) if ends_hunk(body, consumed) => (consumed, None),
+        _ => {
+            let consumed = uncounted_len(body);
+            let warning = match counts {
+                Some((_, old_len, _, new_len)) => {
+                    let (old_found, new_found) = side_counts(&body[..consumed]);
+                    format!(
+                        "Hunk {} declares {} old and {} new lines but has {} and {}; read up to the next header",
+                        header, old_len, new_len, old_found, new_found
+                    )
+                }
+                None => format!(
+                    "Hunk header {} has no line numbers; read up to the next header",
+                    header
+                ),
+            };
+            (consumed, Some(warning))
+        }
+    };
+    if consumed == 0 {
+        return Err(anyhow!("Hunk {} has no lines", header));
+    }
+    let (mut old_text, mut new_text) = hunk_text(&body[..consumed]);
+    // Match the markdown protocol, whose Search/Content carry no final newline
+    if old_text.ends_with('\n') && new_text.ends_with('\n') {
+        old_text.pop();
+        new_text.pop();
+    }
+    // With no old lines the new start is where the insertion goes; otherwise the hunk's
+    // position only breaks ties between identical matches. A hunk that only removes
+    // lines names the line before them.
+    let line_hint = counts.map(|(_, _, new_start, _)| {
+        if side_counts(&body[..consumed]).1 == 0 {
+            new_start + 1
+        } else {
+            new_start.max(1)
+        }
+    });
+    let change = Change {
+        index: 0,
+        description: header.to_string(),
+        search: Some(old_text),
+        content: new_text,
+        occurrence: None,
+        line_hint,
+    };
+    Ok((change, consumed, warning))
+}
+
+// Lines the header's counts cover, or None when the body does not have them
+fn counted_len(body: &[&str], mut old_left: usize, mut new_left: usize) -> Option<usize> {
+    let mut consumed = 0;
+    while consumed < body.len() {
+        let line = body[consumed];
+        if line.starts_with('\\') {
+            consumed += 1;
+            continue;
+        }
+        if old_left == 0 && new_left == 0 {
+            break;
+        }
+        // Some tools drop the single space of an empty context line
+        let marker = match line.chars().next() {
+            Some(c @ (' ' | '-' | '+')) => c,
+            None => ' ',
+            Some(_) => return None,
+        };
+        if marker != '+' {
+            old_left = old_left.checked_sub(1)?;
+        }
+        if marker != '-' {
+            new_left = new_left.checked_sub(1)?;
+        }
+        consumed += 1;
+    }
+    (old_left == 0 && new_left == 0).then_some(consumed)
+}
+
+// Lines up to the next header or the first line that cannot belong to a hunk, without
+// the blank lines in front of it
+fn uncounted_len(body: &[&str]) -> usize {
+    let mut end = 0;
+    while end < body.len() && !is_boundary(body, end) && is_hunk_line(body[end]) {
+        end += 1;
+    }
+    while end > 0 && body[end - 1].is_empty() {
+        end -= 1;
+    }
+    end
+}
+
+// Whether a hunk that stops at `end` leaves nothing of itself behind
+fn ends_hunk(body: &[&str], end: usize) -> bool {
+    let next = (end..body.len()).find(|&j| !body[j].is_empty());
+    next.is_none_or(|j| is_boundary(body, j) || !is_hunk_line(body[j]))
+}
+
+// Where an uncounted hunk must end: the next hunk or file, or the signature separator
+// `git format-patch` puts after the last one
+fn is_boundary(lines: &[&str], i: usize) -> bool {
+    let line = lines[i];
+    line == "-- "
+        || line.starts_with("@@ ")
+        || line.starts_with("diff --git ")
+        || (line.starts_with("--- ") && lines.get(i + 1).is_some_and(|l| l.starts_with("+++ ")))
+}
+
+fn is_hunk_line(line: &str) -> bool {
+    line.is_empty() || line.starts_with([' ', '-', '+', '\\'])
+}
+
+// (old, new) line counts of a hunk body
+fn side_counts(lines: &[&str]) -> (usize, usize) {
+    lines
+        .iter()
+        .filter(|line| !line.starts_with('\\'))
+        .fold((0, 0), |(old, new), line| match line.chars().next() {
+            Some('+') => (old, new + 1),
+            Some('-') => (old + 1, new),
+            _ => (old + 1, new + 1),
+        })
+}
+
+fn hunk_text(lines: &[&str]) -> (String, String) {
+    let mut old_text = String::new();
+    let mut new_text = String::new();
+    // Which sides the previous line went to, for "\ No newline at end of file"
+    let mut last = (false, false);
+    for line in lines {
+        if line.starts_with('\\') {
+            if last.0 && old_text.ends_with('\n') {
+                old_text.pop();
+            }
+            if last.1 && new_text.ends_with('\n') {
+                new_text.pop();
+            }
+            continue;
+        }
+        let (marker, text) = match line.chars().next() {
+            Some(c @ (' ' | '-' | '+')) => (c, &line[1..]),
+            _ => (' ', ""),
+        };
+        last = (marker != '+', marker != '-');
+        if last.0 {
+            old_text.push_str(text);
+            old_text.push('\n');
+        }
+        if last.1 {
+            new_text.push_str(text);
+            new_text.push('\n');
+        }
+    }
+    (old_text, new_text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn modify(diff: &str) -> (Vec<Change>, Vec<Diagnostic>) {
+        let (file_changes, warnings) = parse_unified_diff(diff).unwrap();
+        assert_eq!(file_changes.len(), 1);
+        assert!(matches!(file_changes[0].action, Action::Modify));
+        (file_changes[0].changes.clone(), warnings)
+    }
+
+    #[test]
+    fn reads_hunks_by_their_counts() {
+        let diff = "--- a/x.txt\n+++ b/x.txt\n@@ -1,3 +1,3 @@\n one\n--- two\n+++ 2\n three\n";
+        let (changes, warnings) = modify(diff);
+        assert!(warnings.is_empty());
+        assert_eq!(changes[0].search.as_deref(), Some("one\n-- two\nthree"));
+        assert_eq!(changes[0].content, "one\n++ 2\nthree");
+        assert_eq!(changes[0].line_hint, Some(1));
+    }
+
+    #[test]
+    fn tolerates_wrong_counts() {
+        let diff = "--- a/x.txt\n+++ b/x.txt\n@@ -1,2 +1,2 @@\n one\n-two\n+2\n+2.5\n three\n@@ -10,1 +11,1 @@\n-ten\n+10\n";
+        let (changes, warnings) = modify(diff);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].search.as_deref(), Some("one\ntwo\nthree"));
+        assert_eq!(changes[0].content, "one\n2\n2.5\nthree");
+        assert_eq!(changes[1].content, "10");
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].line, 3);
+        assert!(matches!(warnings[0].severity, Severity::Warning));
+    }
+
+    #[test]
+    fn tolerates_oversized_counts() {
+        let diff = "--- a/x.txt\n+++ b/x.txt\n@@ -1,9 +1,9 @@\n-one\n+1\n\nThat should do it.\n";
+        let (changes, warnings) = modify(diff);
+        assert_eq!(changes[0].search.as_deref(), Some("one"));
+        assert_eq!(changes[0].content, "1");
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn tolerates_headers_without_numbers() {
+        let diff = "--- a/x.txt\n+++ b/x.txt\n@@ @@\n fn a() {\n-    old();\n+    new();\n }\n--- a/y.txt\n+++ b/y.txt\n@@ @@\n-y\n+z\n";
+        let (file_changes, warnings) = parse_unified_diff(diff).unwrap();
+        assert_eq!(file_changes.len(), 2);
+        let change = &file_changes[0].changes[0];
+        assert_eq!(change.search.as_deref(), Some("fn a() {\n    old();\n}"));
+        assert_eq!(change.line_hint, None);
+        assert_eq!(file_changes[1].changes[0].content, "z");
+        assert_eq!(warnings.len(), 2);
+    }
+
+    #[test]
+    fn stops_at_format_patch_signature() {
+        let diff = "diff --git a/x.txt b/x.txt\n--- a/x.txt\n+++ b/x.txt\n@@ -1 +1 @@\n-a\n+b\n-- \n2.43.0\n";
+        let (changes, warnings) = modify(diff);
+        assert!(warnings.is_empty());
+        assert_eq!(changes[0].content, "b");
+    }
+
+    #[test]
+    fn keeps_missing_final_newlines() {
+        let diff = "--- a/x.txt\n+++ b/x.txt\n@@ -1 +1 @@\n-a\n\\ No newline at end of file\n+b\n";
+        let (changes, _) = modify(diff);
+        assert_eq!(changes[0].search.as_deref(), Some("a"));
+        assert_eq!(changes[0].content, "b\n");
+    }
+
+    #[test]
+    fn reads_git_renames_and_new_files() {
+        let diff = "diff --git a/old.txt b/new.txt\nsimilarity index 100%\nrename from old.txt\nrename to new.txt\ndiff --git a/n.sh b/n.sh\nnew file mode 100755\n--- /dev/null\n+++ b/n.sh\n@@ -0,0 +1,2 @@\n+#!/bin/sh\n+echo hi\n";
+        let (file_changes, warnings) = parse_unified_diff(diff).unwrap();
+        assert!(warnings.is_empty());
+        assert!(matches!(file_changes[0].action, Action::Rename));
+        assert_eq!(file_changes[0].target, Some(PathBuf::from("new.txt")));
+        assert!(matches!(file_changes[1].action, Action::Create));
+        assert_eq!(file_changes[1].mode, Some(0o755));
+        assert_eq!(file_changes[1].changes[0].content, "#!/bin/sh\necho hi\n");
+    }
+
+    #[test]
+    fn rejects_binary_patches() {
+        let diff = "diff --git a/i.png b/i.png\nBinary files a/i.png and b/i.png differ\n";
+        assert!(parse_unified_diff(diff).is_err());
+    }
+}
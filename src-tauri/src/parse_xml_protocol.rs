@@ -0,0 +1,328 @@
+use crate::change_types::{Action, Change, FileChange, Occurrence};
+use crate::parse_change_protocol::{
+    parse_action, parse_hash, parse_mode, parse_occurrence, parse_target,
+};
+use anyhow::{anyhow, Context, Result};
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::path::PathBuf;
+
+// Attributes of a <file> element
+struct FileHeader {
+    path: String,
+    action: Option<Action>,
+    mode: Option<u32>,
+    hash: Option<String>,
+    target: Option<String>,
+}
+
+// Text of a <description>, <search> or <content> element, each part flagged with
+// whether it came from a CDATA section
+struct FieldText {
+    parts: Vec<(bool, String)>,
+}
+
+// "<file" followed by whitespace, ">" or "/"
+fn file_tag_at(input: &str, index: usize) -> bool {
+    input[index..].starts_with("<file")
+        && input[index + 5..]
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_whitespace() || c == '>' || c == '/')
+}
+
+pub fn is_file_tag(line: &str) -> bool {
+    file_tag_at(line.trim_start(), 0)
+}
+
+// The plan may sit in prose, a code fence or a root element of any name; only the span
+// from the first <file> to the end of the last one is read as XML. Returns where that span
+// starts, too.
+fn xml_body(input: &str) -> Result<(usize, &str)> {
+    let starts: Vec<usize> = input
+        .match_indices("<file")
+        .map(|(index, _)| index)
+        .filter(|&index| file_tag_at(input, index))
+        .collect();
+    let (first, last) = match (starts.first(), starts.last()) {
+        (Some(first), Some(last)) => (*first, *last),
+        _ => return Err(anyhow!("No <file> elements found")),
+    };
+    let mut end = input
+        .rfind("</file>")
+        .map_or(0, |index| index + "</file>".len());
+    if end <= last {
+        // The last file is a self-closing element such as <file path="x" action="delete"/>
+        end = input[last..]
+            .find("/>")
+            .map(|index| last + index + 2)
+            .ok_or_else(|| anyhow!("Unclosed <file> element"))?;
+    }
+    Ok((first, &input[first..end]))
+}
+
+fn attribute(element: &BytesStart, name: &str) -> Result<Option<String>> {
+    match element.try_get_attribute(name)? {
+        Some(attr) => Ok(Some(attr.unescape_value()?.into_owned())),
+        None => Ok(None),
+    }
+}
+
+fn file_header(element: &BytesStart) -> Result<FileHeader> {
+    let path = attribute(element, "path")?
+        .ok_or_else(|| anyhow!("<file> element without a path attribute"))?;
+    let action_str = attribute(element, "action")?
+        .ok_or_else(|| anyhow!("Missing action for file: {}", path))?;
+    let mode = match attribute(element, "mode")? {
+        Some(mode) => Some(parse_mode(mode.trim())?),
+        None => None,
+    };
+    Ok(FileHeader {
+        action: parse_action(action_str.trim())?,
+        mode,
+        hash: match attribute(element, "hash")? {
+            Some(hash) if !hash.trim().is_empty() => Some(parse_hash(&hash)?),
+            _ => None,
+        },
+        target: attribute(element, "to")?,
+        path,
+    })
+}
+
+fn occurrence(element: &BytesStart) -> Result<Option<Occurrence>> {
+    match attribute(element, "occurrence")? {
+        Some(value) => Ok(Some(parse_occurrence(value.trim())?)),
+        None => Ok(None),
+    }
+}
+
+impl FieldText {
+    // CDATA is taken verbatim and the whitespace around it is layout; without CDATA the
+    // element text is used. Either way the newline after the opening tag and the
+    // indentation before the closing tag are not part of the value.
+    fn value(self) -> String {
+        let cdata = self.parts.iter().any(|(cdata, _)| *cdata);
+        let text: String = self
+            .parts
+            .into_iter()
+            .filter(|(is_cdata, _)| *is_cdata == cdata)
+            .map(|(_, text)| text)
+            .collect();
+        let text = text
+            .strip_prefix("\r\n")
+            .or_else(|| text.strip_prefix('\n'))
+            .unwrap_or(&text);
+        match text.rfind('\n') {
+            Some(index) if text[index..].trim().is_empty() => {
+                text[..index].trim_end_matches('\r').to_string()
+            }
+            _ => text.to_string(),
+        }
+    }
+}
+
+fn push_file(
+    file_changes: &mut Vec<FileChange>,
+    header: FileHeader,
+    changes: Vec<Change>,
+) -> Result<()> {
+    // Files with the no-op action "none" are skipped
+    let Some(action) = header.action else {
+        return Ok(());
+    };
+    let target = parse_target(&header.path, &Some(action.clone()), header.target)?;
+    file_changes.push(FileChange {
+        index: file_changes.len(),
+        path: PathBuf::from(header.path),
+        action,
+        changes,
+        mode: header.mode,
+        hash: header.hash,
+        target,
+        final_newline: None,
+        excluded: Vec::new(),
+    });
+    Ok(())
+}
+
+// Byte positions are reported relative to the plan, not the <plan> wrapper around it
+const WRAPPER_LEN: usize = "<plan>".len();
+
+pub fn parse_xml_protocol(input: &str) -> Result<Vec<FileChange>> {
+    let (body_start, body) = xml_body(input)?;
+    // Offset of a reader position in `input`
+    let in_input = |position: u64| (position as usize + body_start).saturating_sub(WRAPPER_LEN);
+    let wrapped = format!("<plan>{}</plan>", body);
+    let mut reader = Reader::from_str(&wrapped);
+    let mut file_changes: Vec<FileChange> = Vec::new();
+    let mut file: Option<(FileHeader, Vec<Change>)> = None;
+    let mut change: Option<Change> = None;
+    let mut field: Option<(String, FieldText)> = None;
+
+    loop {
+        // Where the next event starts, so errors point at the start of an element
+        let position = in_input(reader.buffer_position());
+        let event = reader.read_event().map_err(|e| {
+            anyhow!(
+                "Invalid XML near byte {}: {}",
+                in_input(reader.error_position()),
+                e
+            )
+        })?;
+        let (element, empty) = match event {
+            Event::Start(element) => (element, false),
+            Event::Empty(element) => (element, true),
+            Event::End(element) => {
+                match element.name().as_ref() {
+                    b"file" => {
+                        if let Some((header, changes)) = file.take() {
+                            push_file(&mut file_changes, header, changes)?;
+                        }
+                    }
+                    b"change" => {
+                        if let (Some((_, changes)), Some(done)) = (file.as_mut(), change.take()) {
+                            changes.push(done);
+                        }
+                    }
+                    b"description" | b"search" | b"content" => {
+                        if let (Some(current), Some((name, text))) = (change.as_mut(), field.take())
+                        {
+                            set_field(current, &name, text.value());
+                        }
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            Event::Text(text) => {
+                if let Some((_, field_text)) = field.as_mut() {
+                    field_text
+                        .parts
+                        .push((false, text.unescape()?.into_owned()));
+                }
+                continue;
+            }
+            Event::CData(text) => {
+                if let Some((_, field_text)) = field.as_mut() {
+                    let text = String::from_utf8(text.into_inner().into_owned())
+                        .context("CDATA section is not valid UTF-8")?;
+                    field_text.parts.push((true, text));
+                }
+                continue;
+            }
+            Event::Eof => break,
+            _ => continue,
+        };
+
+        let name = String::from_utf8_lossy(element.name().as_ref()).into_owned();
+        match name.as_str() {
+            "plan" => {}
+            "file" if file.is_none() => {
+                let header = file_header(&element)?;
+                if empty {
+                    push_file(&mut file_changes, header, Vec::new())?;
+                } else {
+                    file = Some((header, Vec::new()));
+                }
+            }
+            "change" if file.is_some() && change.is_none() => {
+                let new_change = Change {
+                    index: 0,
+                    description: String::new(),
+                    search: None,
+                    content: String::new(),
+                    occurrence: occurrence(&element)?,
+                    line_hint: None,
+                };
+                match (empty, file.as_mut()) {
+                    (true, Some((_, changes))) => changes.push(new_change),
+                    _ => change = Some(new_change),
+                }
+            }
+            "description" | "search" | "content" if change.is_some() && field.is_none() => {
+                if empty {
+                    set_field(change.as_mut().unwrap(), &name, String::new());
+                } else {
+                    field = Some((name, FieldText { parts: Vec::new() }));
+                }
+            }
+            _ => {
+                return Err(anyhow!(
+                    "Unexpected <{}> element near byte {}",
+                    name,
+                    position
+                ))
+            }
+        }
+    }
+
+    if let Some((header, _)) = file {
+        return Err(anyhow!("Unclosed <file> element for: {}", header.path));
+    }
+    Ok(file_changes)
+}
+
+fn set_field(change: &mut Change, name: &str, value: String) {
+    match name {
+        "description" => change.description = value.trim().to_string(),
+        "search" => change.search = Some(value),
+        _ => change.content = value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn joins_split_cdata_sections() {
+        let input = "<plan>\n<file path=\"a.xml\" action=\"create\">\n<change>\n<content>\n<![CDATA[<x>]]]]><![CDATA[></x>\n  & <y/>]]>\n</content>\n</change>\n</file>\n</plan>";
+        let files = parse_xml_protocol(input).unwrap();
+        assert_eq!(files[0].changes[0].content, "<x>]]></x>\n  & <y/>");
+    }
+
+    #[test]
+    fn reads_attributes_and_escaped_text() {
+        let input = "Here:\n<file path=\"a.rs\" action=\"modify\" hash=\"0123abcd\">\n  <change occurrence=\"2\">\n    <description>Bump</description>\n    <search>\nx &lt; 1\n    </search>\n    <content>x &lt; 2</content>\n  </change>\n</file>\n<file path=\"b.rs\" action=\"rename\" to=\"c.rs\"/>\nThanks";
+        let files = parse_xml_protocol(input).unwrap();
+        assert_eq!(files.len(), 2);
+        let change = &files[0].changes[0];
+        assert_eq!(files[0].hash.as_deref(), Some("0123abcd"));
+        assert_eq!(change.occurrence, Some(Occurrence::Nth(2)));
+        assert_eq!(change.description, "Bump");
+        assert_eq!(change.search.as_deref(), Some("x < 1"));
+        assert_eq!(change.content, "x < 2");
+        assert_eq!(files[1].target, Some(PathBuf::from("c.rs")));
+    }
+
+    #[test]
+    fn refuses_unknown_elements_with_their_position() {
+        let input = "<file path=\"a\" action=\"create\"><change><body>x</body></change></file>";
+        let err = parse_xml_protocol(input).unwrap_err().to_string();
+        assert_eq!(err, "Unexpected <body> element near byte 39");
+        assert_eq!(&input[39..45], "<body>");
+        let err = parse_xml_protocol(&format!("Prose.\n{}", input)).unwrap_err();
+        assert_eq!(err.to_string(), "Unexpected <body> element near byte 46");
+
+        let err = parse_xml_protocol(
+            "<file path=\"a\" action=\"create\"><change><content>x</search></change></file>",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.starts_with("Invalid XML near byte "), "{}", err);
+    }
+
+    #[test]
+    fn refuses_files_without_path_or_action() {
+        let err = parse_xml_protocol("<file action=\"delete\"/>")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "<file> element without a path attribute");
+        let err = parse_xml_protocol("<file path=\"a\"/>")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "Missing action for file: a");
+        assert!(parse_xml_protocol("<file path=\"a\" action=\"explode\"/>").is_err());
+        assert!(parse_xml_protocol("no files here").is_err());
+    }
+}
@@ -0,0 +1,364 @@
+use crate::apply_changes::confirmed_set;
+use crate::apply_file_change::{
+    change_results, compute_file_change, normalize_path, read_existing, resolve_plan_paths,
+    target_path,
+};
+use crate::change_types::{Action, ApplyOptions, FileChange, FilePreview, PreviewReport};
+use crate::dir_actions::list_tree;
+use crate::extract_plans::select_plan;
+use crate::parse_plan::parse_plan;
+use crate::sandbox_policy::SandboxPolicy;
+use crate::select_changes::select_changes;
+use anyhow::{anyhow, Context, Result};
+use similar::{ChangeTag, TextDiff};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Runs the same matching logic as apply_changes but never writes; each FileChange
+// is reported with its before/after text and a unified diff.
+pub fn preview_changes(xml_protocol: &str, options: &ApplyOptions) -> Result<PreviewReport> {
+    let project_root = options.project_root.as_deref();
+    let selected = select_plan(xml_protocol, options.plan_index)?;
+    let plan =
+        parse_plan(&selected).context("Failed to parse the change management protocol XML")?;
+    let parsed = select_changes(plan.file_changes, options.include.as_deref())?;
+    let warnings = plan.warnings;
+    log::debug!("Previewing {} FileChange entries", parsed.len());
+    let resolved = resolve_plan_paths(&parsed, project_root).context("Plan rejected")?;
+    let policy = match project_root {
+        Some(root) => Some(SandboxPolicy::load(&normalize_path(root))?),
+        None => None,
+    };
+    let confirmed = project_root
+        .map(|root| confirmed_set(options, &normalize_path(root)))
+        .unwrap_or_default();
+
+    // Later FileChanges for the same path see the output of earlier ones
+    let mut overlay: HashMap<PathBuf, Option<String>> = HashMap::new();
+    let mut previews = Vec::new();
+    for (fc, resolved_path) in parsed.iter().zip(resolved) {
+        let preview = match preview_file_change(
+            fc,
+            resolved_path,
+            policy.as_ref(),
+            &confirmed,
+            options,
+            &mut overlay,
+        ) {
+            Ok(preview) => preview,
+            Err(err) => FilePreview {
+                path: fc.path.clone(),
+                action: fc.action.clone(),
+                target: fc.target.clone(),
+                before: None,
+                after: None,
+                diff: String::new(),
+                added: 0,
+                removed: 0,
+                errors: err.chain().map(|cause| cause.to_string()).collect(),
+                changes: change_results(&err),
+                listing: Vec::new(),
+            },
+        };
+        previews.push(preview);
+    }
+    Ok(PreviewReport {
+        files: previews,
+        warnings,
+    })
+}
+
+fn preview_file_change(
+    fc: &FileChange,
+    resolved_path: PathBuf,
+    policy: Option<&SandboxPolicy>,
+    confirmed: &HashSet<PathBuf>,
+    options: &ApplyOptions,
+    overlay: &mut HashMap<PathBuf, Option<String>>,
+) -> Result<FilePreview> {
+    check_policy(policy, confirmed, fc, &resolved_path, options)?;
+    if fc.is_tree_change() {
+        let listing = preview_tree_action(fc, &resolved_path, options, overlay)?;
+        return Ok(FilePreview {
+            path: fc.path.clone(),
+            action: fc.action.clone(),
+            target: fc.target.clone(),
+            before: None,
+            after: None,
+            diff: String::new(),
+            added: 0,
+            removed: 0,
+            errors: Vec::new(),
+            changes: Vec::new(),
+            listing,
+        });
+    }
+    let before = overlay_state(overlay, &resolved_path)?;
+    let (after, changes) = compute_file_change(fc, &resolved_path, before.as_deref(), options)?;
+    let old_label = fc.path.display().to_string();
+    let new_label = match &fc.action {
+        Action::Rename => {
+            let target = target_path(fc, &resolved_path, options)?;
+            if overlay_state(overlay, &target)?.is_some() {
+                return Err(anyhow!(
+                    "Refusing to overwrite existing path: {}",
+                    target.display()
+                ));
+            }
+            overlay.insert(target, after.clone());
+            overlay.insert(resolved_path, None);
+            fc.target
+                .as_ref()
+                .map_or(old_label.clone(), |t| t.display().to_string())
+        }
+        _ => {
+            overlay.insert(resolved_path, after.clone());
+            old_label.clone()
+        }
+    };
+    let (diff, added, removed) =
+        unified_diff(&old_label, &new_label, before.as_deref(), after.as_deref());
+    Ok(FilePreview {
+        path: fc.path.clone(),
+        action: fc.action.clone(),
+        target: fc.target.clone(),
+        before,
+        after,
+        diff,
+        added,
+        removed,
+        errors: Vec::new(),
+        changes,
+        listing: Vec::new(),
+    })
+}
+
+// The same containment and protection rules apply enforces, for the path, its target and
+// (for directory actions) everything inside; confirmed paths pass as they would in apply
+fn check_policy(
+    policy: Option<&SandboxPolicy>,
+    confirmed: &HashSet<PathBuf>,
+    fc: &FileChange,
+    path: &Path,
+    options: &ApplyOptions,
+) -> Result<()> {
+    let Some(policy) = policy else {
+        return Ok(());
+    };
+    let target = match fc.target {
+        Some(_) => Some(target_path(fc, path, options)?),
+        None => None,
+    };
+    for touched in std::iter::once(path).chain(target.as_deref()) {
+        policy.check_containment(touched)?;
+    }
+    if let Some((_, reason)) =
+        policy.unconfirmed_protected(&fc.action, path, target.as_deref(), confirmed)?
+    {
+        return Err(anyhow!("{}; confirmation required to apply", reason));
+    }
+    Ok(())
+}
+
+fn overlay_exists(overlay: &HashMap<PathBuf, Option<String>>, path: &Path) -> bool {
+    match overlay.get(path) {
+        Some(state) => state.is_some(),
+        None => fs::symlink_metadata(path).is_ok(),
+    }
+}
+
+// Mirrors the checks apply makes for directory actions, copies and plain renames, and
+// returns what a delete-dir would remove or a copy or rename would take along
+fn preview_tree_action(
+    fc: &FileChange,
+    path: &Path,
+    options: &ApplyOptions,
+    overlay: &mut HashMap<PathBuf, Option<String>>,
+) -> Result<Vec<PathBuf>> {
+    match fc.action {
+        Action::CreateDir => {
+            if overlay_exists(overlay, path) {
+                return Err(anyhow!("Path already exists: {}", path.display()));
+            }
+            Ok(Vec::new())
+        }
+        Action::DeleteDir => {
+            if !path.is_dir() {
+                return Err(anyhow!(
+                    "Cannot delete, directory does not exist: {}",
+                    path.display()
+                ));
+            }
+            let listing = list_tree(path)?;
+            for entry in &listing {
+                overlay.insert(path.join(entry), None);
+            }
+            Ok(listing)
+        }
+        Action::Copy => {
+            let target = target_path(fc, path, options)?;
+            if !overlay_exists(overlay, path) {
+                return Err(anyhow!(
+                    "Cannot copy, path does not exist: {}",
+                    path.display()
+                ));
+            }
+            if overlay_exists(overlay, &target) {
+                return Err(anyhow!(
+                    "Refusing to overwrite existing path: {}",
+                    target.display()
+                ));
+            }
+            if path.is_dir() {
+                return list_tree(path);
+            }
+            let copied = overlay_state(overlay, path)?;
+            overlay.insert(target, copied);
+            Ok(Vec::new())
+        }
+        Action::Rename => {
+            let target = target_path(fc, path, options)?;
+            if !overlay_exists(overlay, path) {
+                return Err(anyhow!(
+                    "Cannot rename, path does not exist: {}",
+                    path.display()
+                ));
+            }
+            if overlay_exists(overlay, &target) {
+                return Err(anyhow!(
+                    "Refusing to overwrite existing path: {}",
+                    target.display()
+                ));
+            }
+            if path.is_dir() {
+                let listing = list_tree(path)?;
+                for entry in &listing {
+                    overlay.insert(path.join(entry), None);
+                }
+                return Ok(listing);
+            }
+            let moved = overlay_state(overlay, path)?;
+            overlay.insert(target, moved);
+            overlay.insert(path.to_path_buf(), None);
+            Ok(Vec::new())
+        }
+        _ => Err(anyhow!(
+            "{:?} is not a tree action: {}",
+            fc.action,
+            path.display()
+        )),
+    }
+}
+
+// Text of a path as this preview sees it: staged by an earlier FileChange, else on disk
+fn overlay_state(
+    overlay: &HashMap<PathBuf, Option<String>>,
+    path: &Path,
+) -> Result<Option<String>> {
+    match overlay.get(path) {
+        Some(state) => Ok(state.clone()),
+        None => Ok(read_existing(path)?.map(|(text, _)| text)),
+    }
+}
+
+// Missing sides are diffed as empty text and labelled /dev/null, like git does
+pub fn unified_diff(
+    old_path: &str,
+    new_path: &str,
+    before: Option<&str>,
+    after: Option<&str>,
+) -> (String, usize, usize) {
+    let diff = TextDiff::from_lines(before.unwrap_or(""), after.unwrap_or(""));
+    let mut added = 0;
+    let mut removed = 0;
+    for change in diff.iter_all_changes() {
+        match change.tag() {
+            ChangeTag::Insert => added += 1,
+            ChangeTag::Delete => removed += 1,
+            ChangeTag::Equal => {}
+        }
+    }
+    let old_header = match before {
+        Some(_) => format!("a/{}", old_path.trim_start_matches('/')),
+        None => "/dev/null".to_string(),
+    };
+    let new_header = match after {
+        Some(_) => format!("b/{}", new_path.trim_start_matches('/')),
+        None => "/dev/null".to_string(),
+    };
+    let text = diff
+        .unified_diff()
+        .context_radius(3)
+        .header(&old_header, &new_header)
+        .to_string();
+    (text, added, removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch_dir;
+
+    #[test]
+    fn confirmed_paths_are_previewed() {
+        let root = scratch_dir("preview-confirmed");
+        fs::write(root.join(".env"), "A=1\n").unwrap();
+        let plan = "### File .env\n### Action rewrite\n#### Change\n**Content**:\n```\nA=2\n```\n";
+        let options = ApplyOptions {
+            project_root: Some(root.clone()),
+            ..Default::default()
+        };
+        let report = preview_changes(plan, &options).unwrap();
+        assert!(report.files[0].errors[0].contains("confirmation required"));
+
+        let options = ApplyOptions {
+            confirmed_paths: vec![PathBuf::from(".env")],
+            ..options
+        };
+        let report = preview_changes(plan, &options).unwrap();
+        assert!(
+            report.files[0].errors.is_empty(),
+            "{:?}",
+            report.files[0].errors
+        );
+        assert_eq!(report.files[0].after.as_deref(), Some("A=2\n"));
+        assert_eq!(fs::read_to_string(root.join(".env")).unwrap(), "A=1\n");
+    }
+
+    #[test]
+    fn diffs_and_counts_each_file() {
+        let root = scratch_dir("preview-diff");
+        fs::write(root.join("a.txt"), "one\ntwo\nthree\n").unwrap();
+        fs::write(root.join("c.txt"), "gone\nsoon\n").unwrap();
+        let plan = "### File a.txt\n### Action modify\n#### Change\n**Search**:\n```\ntwo\n```\n**Content**:\n```\n2\nand a half\n```\n### File b.txt\n### Action create\n#### Change\n**Content**:\n```\nnew\n```\n### File c.txt\n### Action delete\n";
+        let options = ApplyOptions {
+            project_root: Some(root.clone()),
+            ..Default::default()
+        };
+        let report = preview_changes(plan, &options).unwrap();
+        let files: Vec<_> = report
+            .files
+            .iter()
+            .map(|file| (file.diff.as_str(), file.added, file.removed))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                (
+                    "--- a/a.txt\n+++ b/a.txt\n@@ -1,3 +1,4 @@\n one\n-two\n+2\n+and a half\n three\n",
+                    2,
+                    1
+                ),
+                ("--- /dev/null\n+++ b/b.txt\n@@ -0,0 +1 @@\n+new\n", 1, 0),
+                ("--- a/c.txt\n+++ /dev/null\n@@ -1,2 +0,0 @@\n-gone\n-soon\n", 0, 2),
+            ]
+        );
+        // Nothing is written
+        assert!(!root.join("b.txt").exists());
+        assert_eq!(
+            fs::read_to_string(root.join("c.txt")).unwrap(),
+            "gone\nsoon\n"
+        );
+    }
+}
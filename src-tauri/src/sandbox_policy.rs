@@ -0,0 +1,238 @@
+use crate::change_types::Action;
+use crate::dir_actions::list_tree;
+use anyhow::{anyhow, Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Project-level policy file: gitignore syntax, one pattern per line.
+// Patterns add to the defaults below and `!pattern` lifts a default.
+pub const POLICY_FILE: &str = ".o11n";
+
+const DEFAULT_PROTECTED: &[&str] = &[
+    ".git/",
+    ".env*",
+    POLICY_FILE,
+    "package-lock.json",
+    "yarn.lock",
+    "pnpm-lock.yaml",
+    "bun.lockb",
+    "Cargo.lock",
+    "Gemfile.lock",
+    "poetry.lock",
+    "composer.lock",
+];
+
+// Guards every write driven by a plan: paths must stay inside the project root after
+// following symlinks, and protected paths need explicit confirmation.
+pub struct SandboxPolicy {
+    canonical_root: PathBuf,
+    protected: Gitignore,
+}
+
+impl SandboxPolicy {
+    pub fn load(project_root: &Path) -> Result<Self> {
+        let canonical_root = fs::canonicalize(project_root).context(format!(
+            "Project root does not exist: {}",
+            project_root.display()
+        ))?;
+        let mut builder = GitignoreBuilder::new(project_root);
+        for pattern in DEFAULT_PROTECTED {
+            builder.add_line(None, pattern)?;
+        }
+        let policy_file = project_root.join(POLICY_FILE);
+        if policy_file.is_file() {
+            if let Some(err) = builder.add(&policy_file) {
+                return Err(anyhow!(
+                    "Invalid policy file {}: {}",
+                    policy_file.display(),
+                    err
+                ));
+            }
+        }
+        let protected = builder.build()?;
+        Ok(Self {
+            canonical_root,
+            protected,
+        })
+    }
+
+    // Follows symlinks on the longest existing prefix of `path`, so both existing files
+    // and files about to be created are checked against where they would really land.
+    pub fn check_containment(&self, path: &Path) -> Result<()> {
+        let mut existing = path;
+        let mut remainder = Vec::new();
+        while fs::symlink_metadata(existing).is_err() {
+            match (existing.parent(), existing.file_name()) {
+                (Some(parent), Some(name)) => {
+                    remainder.push(name.to_os_string());
+                    existing = parent;
+                }
+                _ => break,
+            }
+        }
+        let mut real = fs::canonicalize(existing).context(format!(
+            "Could not resolve symlinks for: {}",
+            existing.display()
+        ))?;
+        real.extend(remainder.iter().rev());
+        if !real.starts_with(&self.canonical_root) {
+            return Err(anyhow!(
+                "Path {} escapes the project root via a symlink (resolves to {})",
+                path.display(),
+                real.display()
+            ));
+        }
+        Ok(())
+    }
+
+    // Returns why the path is protected, or None when it may be written freely
+    pub fn protected_reason(&self, path: &Path) -> Option<String> {
+        let matched = self
+            .protected
+            .matched_path_or_any_parents(path, path.is_dir());
+        if !matched.is_ignore() {
+            return None;
+        }
+        let pattern = matched
+            .inner()
+            .map(|glob| glob.original().to_string())
+            .unwrap_or_default();
+        Some(format!(
+            "Path is protected by the sandbox policy (pattern `{}`)",
+            pattern
+        ))
+    }
+
+    // The first protected path an action touches that the caller has not confirmed, with
+    // the reason. Directory actions touch everything below the directory too: what a
+    // delete-dir removes, what a copy creates under its target, and both sides of a move.
+    // Confirming the directory itself covers what is inside it.
+    pub fn unconfirmed_protected(
+        &self,
+        action: &Action,
+        path: &Path,
+        target: Option<&Path>,
+        confirmed: &HashSet<PathBuf>,
+    ) -> Result<Option<(PathBuf, String)>> {
+        let mut inside = Vec::new();
+        if path.is_dir() && matches!(action, Action::DeleteDir | Action::Copy | Action::Rename) {
+            for entry in list_tree(path)? {
+                if !matches!(action, Action::Copy) {
+                    inside.push((path.join(&entry), path));
+                }
+                if let Some(target) = target {
+                    inside.push((target.join(&entry), target));
+                }
+            }
+        }
+        let touched = std::iter::once((path.to_path_buf(), path))
+            .chain(target.map(|target| (target.to_path_buf(), target)))
+            .chain(inside);
+        for (touched, dir) in touched {
+            if let Some(reason) = self.protected_reason(&touched) {
+                if !confirmed.contains(&touched) && !confirmed.contains(dir) {
+                    let reason = match touched.as_path() == path {
+                        true => reason,
+                        false => format!("{} ({})", reason, touched.display()),
+                    };
+                    return Ok(Some((touched, reason)));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch_dir;
+
+    fn project(name: &str) -> (PathBuf, SandboxPolicy) {
+        let root = scratch_dir(name);
+        fs::create_dir_all(root.join("conf")).unwrap();
+        fs::write(root.join("conf/.env"), "SECRET=1\n").unwrap();
+        fs::write(root.join("conf/app.toml"), "a = 1\n").unwrap();
+        let policy = SandboxPolicy::load(&root).unwrap();
+        (root, policy)
+    }
+
+    #[test]
+    fn protects_defaults_and_policy_file() {
+        let (root, _) = project("sandbox-defaults");
+        fs::write(root.join(POLICY_FILE), "secrets/\n!Cargo.lock\n").unwrap();
+        let policy = SandboxPolicy::load(&root).unwrap();
+        assert!(policy.protected_reason(&root.join(".env.local")).is_some());
+        assert!(policy.protected_reason(&root.join("secrets/key")).is_some());
+        assert!(policy.protected_reason(&root.join("Cargo.lock")).is_none());
+        assert!(policy.protected_reason(&root.join("src/main.rs")).is_none());
+    }
+
+    #[test]
+    fn copying_a_directory_checks_what_it_creates() {
+        let (root, policy) = project("sandbox-copy");
+        let none = HashSet::new();
+        let (touched, reason) = policy
+            .unconfirmed_protected(
+                &Action::Copy,
+                &root.join("conf"),
+                Some(&root.join("public/conf")),
+                &none,
+            )
+            .unwrap()
+            .expect("copy of .env is blocked");
+        assert_eq!(touched, root.join("public/conf/.env"));
+        assert!(reason.contains("public/conf/.env"));
+
+        let confirmed = HashSet::from([root.join("public/conf")]);
+        let allowed = policy
+            .unconfirmed_protected(
+                &Action::Copy,
+                &root.join("conf"),
+                Some(&root.join("public/conf")),
+                &confirmed,
+            )
+            .unwrap();
+        assert!(allowed.is_none());
+    }
+
+    #[test]
+    fn deleting_or_moving_a_directory_checks_its_contents() {
+        let (root, policy) = project("sandbox-delete-move");
+        let none = HashSet::new();
+        let deleted = policy
+            .unconfirmed_protected(&Action::DeleteDir, &root.join("conf"), None, &none)
+            .unwrap();
+        assert_eq!(deleted.map(|(path, _)| path), Some(root.join("conf/.env")));
+
+        let moved = policy
+            .unconfirmed_protected(
+                &Action::Rename,
+                &root.join("conf"),
+                Some(&root.join("settings")),
+                &none,
+            )
+            .unwrap();
+        assert_eq!(moved.map(|(path, _)| path), Some(root.join("conf/.env")));
+
+        let confirmed = HashSet::from([root.join("conf")]);
+        let deleted = policy
+            .unconfirmed_protected(&Action::DeleteDir, &root.join("conf"), None, &confirmed)
+            .unwrap();
+        assert!(deleted.is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlink_escapes() {
+        let (root, policy) = project("sandbox-escape");
+        let outside = scratch_dir("sandbox-escape-outside");
+        std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+        assert!(policy
+            .check_containment(&root.join("link/new.txt"))
+            .is_err());
+        assert!(policy.check_containment(&root.join("conf/new.txt")).is_ok());
+    }
+}
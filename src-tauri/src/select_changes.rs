@@ -0,0 +1,94 @@
+use crate::change_types::{change_id, Action, Change, FileChange};
+use anyhow::{anyhow, Result};
+
+// Narrows a plan to the file changes ("3") and changes ("3:1") listed in `include`.
+// Changes of a modify or rename that were left out stay on the file change as
+// `excluded`, so apply can refuse a selection that splits overlapping edits.
+pub fn select_changes(
+    file_changes: Vec<FileChange>,
+    include: Option<&[String]>,
+) -> Result<Vec<FileChange>> {
+    let Some(include) = include else {
+        return Ok(file_changes);
+    };
+    for id in include {
+        let known = file_changes.iter().any(|file| {
+            file.id() == *id
+                || file
+                    .changes
+                    .iter()
+                    .any(|change| change_id(file.index, change.index) == *id)
+        });
+        if !known {
+            return Err(anyhow!("No file or change with id {} in the plan", id));
+        }
+    }
+    let mut selected = Vec::new();
+    for mut file in file_changes {
+        if include.contains(&file.id()) {
+            selected.push(file);
+            continue;
+        }
+        let file_index = file.index;
+        let (picked, excluded): (Vec<Change>, Vec<Change>) = std::mem::take(&mut file.changes)
+            .into_iter()
+            .partition(|change| include.contains(&change_id(file_index, change.index)));
+        if picked.is_empty() {
+            continue;
+        }
+        // Only edits are matched one by one; the changes of other actions make up a
+        // single piece of content
+        if !matches!(file.action, Action::Modify | Action::Rename) {
+            return Err(anyhow!(
+                "The changes of {} ({}) cannot be applied separately; include file {} instead",
+                file.path.display(),
+                file.action.name(),
+                file.id()
+            ));
+        }
+        file.changes = picked;
+        file.excluded = excluded;
+        selected.push(file);
+    }
+    Ok(selected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_plan::parse_plan;
+
+    const PLAN: &str = "### File a.txt\n### Action modify\n#### Change\n**Search**:\n```\na\n```\n**Content**:\n```\nb\n```\n#### Change\n**Search**:\n```\nc\n```\n**Content**:\n```\nd\n```\n### File b.txt\n### Action create\n#### Change\n**Content**:\n```\nnew\n```\n### File c.txt\n### Action delete\n";
+
+    fn select(include: &[&str]) -> Result<Vec<FileChange>> {
+        let include: Vec<String> = include.iter().map(|id| id.to_string()).collect();
+        select_changes(parse_plan(PLAN).unwrap().file_changes, Some(&include))
+    }
+
+    #[test]
+    fn keeps_everything_without_a_selection() {
+        let files = select_changes(parse_plan(PLAN).unwrap().file_changes, None).unwrap();
+        assert_eq!(files.len(), 3);
+        assert!(files.iter().all(|file| file.excluded.is_empty()));
+    }
+
+    #[test]
+    fn narrows_to_files_and_changes() {
+        let files = select(&["0:1", "2"]).unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].changes.len(), 1);
+        assert_eq!(files[0].changes[0].index, 1);
+        assert_eq!(files[0].excluded.len(), 1);
+        assert_eq!(files[0].excluded[0].index, 0);
+        assert_eq!(files[1].action, Action::Delete);
+    }
+
+    #[test]
+    fn rejects_unknown_ids_and_partial_content() {
+        let err = select(&["0:7"]).unwrap_err().to_string();
+        assert!(err.contains("0:7"), "{}", err);
+        assert!(select(&["9"]).is_err());
+        let err = select(&["1:0"]).unwrap_err().to_string();
+        assert!(err.contains("include file 1"), "{}", err);
+    }
+}
@@ -0,0 +1,961 @@
+use crate::change_types::{
+    Action, Change, EditedPlan, FileChange, FinalNewline, Occurrence, PlanEdit,
+};
+use crate::parse_plan::{assign_ids, parse_plan, PlanFormat};
+use crate::parse_search_replace::is_block_marker;
+use crate::parse_unified_diff::parse_hunk_header;
+use anyhow::{anyhow, Result};
+use similar::{ChangeTag, TextDiff};
+use std::path::Path;
+
+// Writes file changes back out as a plan that parses to the same changes, and fails on
+// what the dialect cannot express. Only diffs place changes by line number and spell out
+// how a file ends, and only diffs can leave those out; the diff and SEARCH/REPLACE
+// dialects also have no occurrences, directory actions, ...
+pub fn serialize_plan(file_changes: &[FileChange], format: PlanFormat) -> Result<String> {
+    let text = match format {
+        PlanFormat::Markdown => to_markdown(file_changes),
+        PlanFormat::Xml => to_xml(file_changes),
+        PlanFormat::SearchReplace => to_search_replace(file_changes)?,
+        PlanFormat::UnifiedDiff => to_unified_diff(file_changes)?,
+    };
+    check_positions(file_changes, format)?;
+    Ok(text)
+}
+
+// Parses a plan, applies the user's edits and writes it back out, in `format` or else
+// in the dialect it came in
+pub fn edit_plan(
+    input: &str,
+    edits: &[PlanEdit],
+    format: Option<PlanFormat>,
+) -> Result<EditedPlan> {
+    let plan = parse_plan(input)?;
+    let format = format.unwrap_or(plan.format);
+    let mut file_changes = plan.file_changes;
+    let mut deleted_files: Vec<usize> = Vec::new();
+    let mut deleted_changes: Vec<(usize, usize)> = Vec::new();
+    for edit in edits {
+        match edit {
+            PlanEdit::DeleteFile { file_index } => {
+                file_at(&mut file_changes, *file_index)?;
+                deleted_files.push(*file_index);
+            }
+            PlanEdit::DeleteChange {
+                file_index,
+                change_index,
+            } => {
+                change_at(&mut file_changes, *file_index, *change_index)?;
+                deleted_changes.push((*file_index, *change_index));
+            }
+            PlanEdit::EditChange {
+                file_index,
+                change_index,
+                description,
+                search,
+                content,
+            } => {
+                let change = change_at(&mut file_changes, *file_index, *change_index)?;
+                if let Some(description) = description {
+                    change.description = description.trim().to_string();
+                }
+                if let Some(search) = search {
+                    change.search = Some(search.clone());
+                }
+                if let Some(content) = content {
+                    change.content = content.clone();
+                }
+            }
+        }
+    }
+    // Deletions go last so every edit addresses the plan as it was parsed
+    let mut file_changes: Vec<FileChange> = file_changes
+        .into_iter()
+        .filter(|file| !deleted_files.contains(&file.index))
+        .map(|mut file| {
+            let changes = std::mem::take(&mut file.changes);
+            file.changes = changes
+                .into_iter()
+                .enumerate()
+                .filter(|(change_index, _)| !deleted_changes.contains(&(file.index, *change_index)))
+                .map(|(_, change)| change)
+                .collect();
+            file
+        })
+        .collect();
+    assign_ids(&mut file_changes);
+    let body = serialize_plan(&file_changes, format)?;
+    Ok(EditedPlan {
+        format,
+        plan: with_narrative(&plan.narrative, format, body),
+        files: file_changes.len(),
+    })
+}
+
+// Puts the plan's prose back in front of the changes
+fn with_narrative(narrative: &str, format: PlanFormat, body: String) -> String {
+    match format {
+        _ if narrative.is_empty() => body,
+        PlanFormat::Markdown => format!("# Plan\n\n{}\n\n## Files\n\n{}", narrative, body),
+        _ => format!("{}\n\n{}", narrative, body),
+    }
+}
+
+fn file_at(file_changes: &mut [FileChange], file_index: usize) -> Result<&mut FileChange> {
+    let count = file_changes.len();
+    file_changes
+        .get_mut(file_index)
+        .ok_or_else(|| anyhow!("No file {} in a plan of {} files", file_index, count))
+}
+
+fn change_at(
+    file_changes: &mut [FileChange],
+    file_index: usize,
+    change_index: usize,
+) -> Result<&mut Change> {
+    let file = file_at(file_changes, file_index)?;
+    let count = file.changes.len();
+    let path = file.path.display().to_string();
+    file.changes.get_mut(change_index).ok_or_else(|| {
+        anyhow!(
+            "No change {} in {}, which has {} changes",
+            change_index,
+            path,
+            count
+        )
+    })
+}
+
+fn format_name(format: PlanFormat) -> &'static str {
+    match format {
+        PlanFormat::Markdown => "markdown",
+        PlanFormat::Xml => "XML",
+        PlanFormat::SearchReplace => "SEARCH/REPLACE blocks",
+        PlanFormat::UnifiedDiff => "a diff",
+    }
+}
+
+// Line positions and final-newline handling come from diffs and only diffs can carry
+// them; a diff in turn always carries both, so a plan without them cannot become one
+fn check_positions(file_changes: &[FileChange], format: PlanFormat) -> Result<()> {
+    let to_diff = format == PlanFormat::UnifiedDiff;
+    for file in file_changes {
+        let path = file.path.display();
+        let writes_text = matches!(
+            file.action,
+            Action::Modify | Action::Rewrite | Action::Create | Action::Rename
+        );
+        let preserves = file.final_newline == Some(FinalNewline::Preserve);
+        if writes_text && file.final_newline.is_some() && !(to_diff && preserves) {
+            return Err(anyhow!(
+                "{}: {} cannot say how the file should end, as the diff it came from does",
+                path,
+                format_name(format)
+            ));
+        }
+        if writes_text && to_diff && !preserves {
+            return Err(anyhow!(
+                "{}: a diff always keeps the file's ending as written, but this change leaves the final newline to the apply options",
+                path
+            ));
+        }
+        let positioned = file.changes.iter().any(|change| change.line_hint.is_some());
+        if positioned && !to_diff {
+            return Err(anyhow!(
+                "{}: {} cannot place a change by line number, as the diff it came from does",
+                path,
+                format_name(format)
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn occurrence_value(occurrence: &Occurrence) -> String {
+    match occurrence {
+        Occurrence::Nth(n) => n.to_string(),
+        Occurrence::All => "all".to_string(),
+    }
+}
+
+// A backtick fence longer than any backtick run that starts a line of the code
+fn fence_for(code: &str) -> String {
+    let longest = code
+        .lines()
+        .map(|line| line.trim_start().chars().take_while(|c| *c == '`').count())
+        .max()
+        .unwrap_or(0);
+    "`".repeat(longest.max(2) + 1)
+}
+
+fn to_markdown(file_changes: &[FileChange]) -> String {
+    let mut out = String::new();
+    for file in file_changes {
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        out.push_str(&format!("### File {}\n", file.path.display()));
+        out.push_str(&format!("### Action {}\n", file.action.name()));
+        if let Some(target) = &file.target {
+            out.push_str(&format!("### To {}\n", target.display()));
+        }
+        if let Some(mode) = file.mode {
+            out.push_str(&format!("### Mode {:o}\n", mode));
+        }
+        if let Some(hash) = &file.hash {
+            out.push_str(&format!("### Hash {}\n", hash));
+        }
+        for change in &file.changes {
+            out.push_str("\n#### Change\n");
+            if !change.description.is_empty() {
+                out.push_str(&format!("**Description**: {}\n", change.description));
+            }
+            if let Some(occurrence) = &change.occurrence {
+                out.push_str(&format!(
+                    "**Occurrence**: {}\n",
+                    occurrence_value(occurrence)
+                ));
+            }
+            let mut push_code = |field: &str, code: &str| {
+                let fence = fence_for(code);
+                out.push_str(&format!("**{}**:\n{}\n{}\n{}\n", field, fence, code, fence));
+            };
+            if let Some(search) = &change.search {
+                push_code("Search", search);
+            }
+            push_code("Content", &change.content);
+        }
+    }
+    out
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Code goes in CDATA so it needs no escaping; a "]]>" inside it is split across two
+// sections. The newlines around it are layout the parser strips again.
+fn cdata(code: &str) -> String {
+    format!("<![CDATA[\n{}\n]]>", code.replace("]]>", "]]]]><![CDATA[>"))
+}
+
+fn to_xml(file_changes: &[FileChange]) -> String {
+    let mut out = String::from("<plan>\n");
+    for file in file_changes {
+        let mut attributes = format!(
+            "path=\"{}\" action=\"{}\"",
+            xml_escape(&file.path.to_string_lossy()),
+            file.action.name()
+        );
+        if let Some(target) = &file.target {
+            attributes.push_str(&format!(
+                " to=\"{}\"",
+                xml_escape(&target.to_string_lossy())
+            ));
+        }
+        if let Some(mode) = file.mode {
+            attributes.push_str(&format!(" mode=\"{:o}\"", mode));
+        }
+        if let Some(hash) = &file.hash {
+            attributes.push_str(&format!(" hash=\"{}\"", xml_escape(hash)));
+        }
+        if file.changes.is_empty() {
+            out.push_str(&format!("<file {}/>\n", attributes));
+            continue;
+        }
+        out.push_str(&format!("<file {}>\n", attributes));
+        for change in &file.changes {
+            match &change.occurrence {
+                Some(occurrence) => out.push_str(&format!(
+                    "  <change occurrence=\"{}\">\n",
+                    occurrence_value(occurrence)
+                )),
+                None => out.push_str("  <change>\n"),
+            }
+            if !change.description.is_empty() {
+                out.push_str(&format!(
+                    "    <description>{}</description>\n",
+                    xml_escape(&change.description)
+                ));
+            }
+            if let Some(search) = &change.search {
+                out.push_str(&format!("    <search>{}</search>\n", cdata(search)));
+            }
+            out.push_str(&format!(
+                "    <content>{}</content>\n",
+                cdata(&change.content)
+            ));
+            out.push_str("  </change>\n");
+        }
+        out.push_str("</file>\n");
+    }
+    out.push_str("</plan>\n");
+    out
+}
+
+// Block text is read line by line, so an empty section is written as no lines at all
+fn block_section(text: &str) -> String {
+    if text.is_empty() {
+        String::new()
+    } else {
+        format!("{}\n", text)
+    }
+}
+
+fn to_search_replace(file_changes: &[FileChange]) -> Result<String> {
+    let mut out = String::new();
+    for file in file_changes {
+        let path = file.path.to_string_lossy();
+        if path.is_empty() || path.contains(char::is_whitespace) {
+            return Err(anyhow!(
+                "{} cannot be named above a SEARCH/REPLACE block: the path contains whitespace",
+                path
+            ));
+        }
+        if file.mode.is_some() {
+            return Err(anyhow!(
+                "{}: SEARCH/REPLACE blocks cannot set a file mode",
+                path
+            ));
+        }
+        let blocks: Vec<(&str, &str)> = match file.action {
+            Action::Modify => file
+                .changes
+                .iter()
+                .map(|change| {
+                    if change.occurrence.is_some() {
+                        return Err(anyhow!(
+                            "{}: SEARCH/REPLACE blocks cannot select an occurrence",
+                            path
+                        ));
+                    }
+                    match change.search.as_deref() {
+                        Some(search) if !search.trim().is_empty() => {
+                            Ok((search, change.content.as_str()))
+                        }
+                        _ => Err(anyhow!(
+                            "{}: a modify change needs a non-empty Search in a SEARCH/REPLACE block",
+                            path
+                        )),
+                    }
+                })
+                .collect::<Result<_>>()?,
+            Action::Create => match file.changes.as_slice() {
+                [] => vec![("", "")],
+                [change] => vec![("", change.content.as_str())],
+                _ => {
+                    return Err(anyhow!(
+                        "{}: a created file must be a single SEARCH/REPLACE block",
+                        path
+                    ))
+                }
+            },
+            _ => {
+                return Err(anyhow!(
+                    "{}: {} cannot be written as a SEARCH/REPLACE block",
+                    path,
+                    file.action.name()
+                ))
+            }
+        };
+        for (search, content) in blocks {
+            if search.lines().chain(content.lines()).any(is_block_marker) {
+                return Err(anyhow!(
+                    "{}: code containing SEARCH/REPLACE markers cannot be written as a block",
+                    path
+                ));
+            }
+            let fence = fence_for(&format!("{}\n{}", search, content));
+            if !out.is_empty() {
+                out.push('\n');
+            }
+            out.push_str(&format!(
+                "{}\n{}\n<<<<<<< SEARCH\n{}=======\n{}>>>>>>> REPLACE\n{}\n",
+                path,
+                fence,
+                block_section(search),
+                block_section(content),
+                fence
+            ));
+        }
+    }
+    Ok(out)
+}
+
+// Paths with characters git would escape are written C-quoted, as git does
+fn diff_path(prefix: &str, path: &Path) -> String {
+    let path = format!("{}{}", prefix, path.to_string_lossy());
+    if !path.contains(['"', '\\', '\t', '\n']) {
+        return path;
+    }
+    let escaped = path
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+fn git_mode(mode: u32) -> String {
+    format!("{:o}", 0o100000 | mode)
+}
+
+fn to_unified_diff(file_changes: &[FileChange]) -> Result<String> {
+    let mut out = String::new();
+    let mut files = file_changes.iter().peekable();
+    while let Some(file) = files.next() {
+        let path = &file.path;
+        let unsupported =
+            |what: &str| anyhow!("{}: {} cannot be written as a diff", path.display(), what);
+        // A hash only guards against stale files, so it is dropped rather than refused
+        match file.action {
+            Action::Create => {
+                out.push_str(&format!(
+                    "diff --git {} {}\n",
+                    diff_path("a/", path),
+                    diff_path("b/", path)
+                ));
+                if let Some(mode) = file.mode {
+                    out.push_str(&format!("new file mode {}\n", git_mode(mode)));
+                }
+                out.push_str(&format!("--- /dev/null\n+++ {}\n", diff_path("b/", path)));
+                let content: String = file.changes.iter().map(|c| c.content.as_str()).collect();
+                if !content.is_empty() {
+                    let (body, _, new_len) = hunk_body("", &content);
+                    out.push_str(&format!("@@ -0,0 +1,{} @@\n{}", new_len, body));
+                }
+            }
+            Action::Delete => {
+                out.push_str(&format!(
+                    "diff --git {} {}\n--- {}\n+++ /dev/null\n",
+                    diff_path("a/", path),
+                    diff_path("b/", path),
+                    diff_path("a/", path)
+                ));
+            }
+            Action::Modify | Action::Rename | Action::Copy => {
+                let target = file.target.as_deref().unwrap_or(path);
+                out.push_str(&format!(
+                    "diff --git {} {}\n",
+                    diff_path("a/", path),
+                    diff_path("b/", target)
+                ));
+                let (from, to) = (diff_path("", path), diff_path("", target));
+                match file.action {
+                    Action::Rename => {
+                        out.push_str(&format!("rename from {}\nrename to {}\n", from, to))
+                    }
+                    Action::Copy => out.push_str(&format!("copy from {}\ncopy to {}\n", from, to)),
+                    _ => {}
+                }
+                if let Some(mode) = file.mode {
+                    out.push_str(&format!("new mode {}\n", git_mode(mode)));
+                }
+                // The diff parser splits a copy with hunks into a copy and a modify of
+                // the new file; write that pair back as one section
+                let changes = match file.action {
+                    Action::Copy => files
+                        .next_if(|next| {
+                            matches!(next.action, Action::Modify)
+                                && Some(&next.path) == file.target.as_ref()
+                                && next.mode == file.mode
+                        })
+                        .map_or(&[][..], |next| next.changes.as_slice()),
+                    _ => file.changes.as_slice(),
+                };
+                if !changes.is_empty() {
+                    out.push_str(&format!(
+                        "--- {}\n+++ {}\n",
+                        diff_path("a/", path),
+                        diff_path("b/", target)
+                    ));
+                }
+                for change in changes {
+                    if change.occurrence.is_some() {
+                        return Err(unsupported("a change with an Occurrence"));
+                    }
+                    let search = change
+                        .search
+                        .as_deref()
+                        .ok_or_else(|| unsupported("a change without Search"))?;
+                    out.push_str(&hunk(search, change));
+                }
+            }
+            _ => return Err(unsupported(file.action.name())),
+        }
+    }
+    Ok(out)
+}
+
+// The parser drops one final newline when both sides of a hunk end with one, so that
+// newline is put back here. An empty Search is a pure insertion and has no old side.
+fn hunk(search: &str, change: &Change) -> String {
+    let content = &change.content;
+    let (body, old_len, new_len) =
+        if search.is_empty() || search.ends_with('\n') != content.ends_with('\n') {
+            hunk_body(search, content)
+        } else {
+            hunk_body(&format!("{}\n", search), &format!("{}\n", content))
+        };
+    let new_start = match change.line_hint {
+        Some(hint) if new_len == 0 => hint.saturating_sub(1),
+        Some(hint) => hint,
+        None => usize::from(new_len > 0),
+    };
+    let old_start = if old_len == 0 {
+        new_start.saturating_sub(1)
+    } else {
+        new_start
+    };
+    // A description that is already this hunk's header (it came from a diff) is kept
+    let header = match parse_hunk_header(&change.description) {
+        Ok((_, old, start, new))
+            if (old, start, new) == (old_len, new_start, new_len)
+                && !change.description.contains('\n') =>
+        {
+            change.description.clone()
+        }
+        _ => {
+            let header = format!(
+                "@@ -{},{} +{},{} @@",
+                old_start, old_len, new_start, new_len
+            );
+            match change.description.lines().next() {
+                Some(summary) if !summary.trim().is_empty() => format!("{} {}", header, summary),
+                _ => header,
+            }
+        }
+    };
+    format!("{}\n{}", header, body)
+}
+
+// Hunk lines for turning `old` into `new`, with the old and new line counts
+fn hunk_body(old: &str, new: &str) -> (String, usize, usize) {
+    let diff = TextDiff::from_lines(old, new);
+    let mut body = String::new();
+    let (mut old_len, mut new_len) = (0, 0);
+    for line in diff.iter_all_changes() {
+        let sign = match line.tag() {
+            ChangeTag::Equal => ' ',
+            ChangeTag::Delete => '-',
+            ChangeTag::Insert => '+',
+        };
+        if sign != '+' {
+            old_len += 1;
+        }
+        if sign != '-' {
+            new_len += 1;
+        }
+        body.push(sign);
+        body.push_str(line.value());
+        if !line.value().ends_with('\n') {
+            body.push_str("\n\\ No newline at end of file\n");
+        }
+    }
+    (body, old_len, new_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // parse -> serialize -> parse must give back the same file changes
+    fn assert_round_trip(input: &str, format: PlanFormat) -> String {
+        let file_changes = parse_plan(input).unwrap().file_changes;
+        let text = serialize_plan(&file_changes, format).unwrap();
+        let again = parse_plan(&text).unwrap();
+        assert_eq!(again.format, format, "{}", text);
+        assert_eq!(again.file_changes, file_changes, "{}", text);
+        assert!(again.warnings.is_empty(), "{:?}", again.warnings);
+        text
+    }
+
+    const MARKDOWN: &str = r#"<pre>
+### File src/main.rs
+### Action modify
+### Hash 1a2b3c4d
+
+#### Change
+**Description**: Rename the greeting
+and explain why
+**Search**:
+```rust
+    println!("hi");
+```
+**Content**:
+```rust
+    println!("hello");  
+```
+
+#### Change
+**Occurrence**: all
+**Search**:
+```
+let x = 1;
+```
+**Content**:
+```
+
+```
+
+### File README.md
+### Action create
+
+#### Change
+**Content**:
+````markdown
+# Title
+
+```sh
+cargo run
+```
+````
+
+### File run.sh
+### Action rewrite
+### Mode executable
+
+#### Change
+**Content**:
+```
+#!/bin/sh
+	echo "tab & <angle> ]]> done"
+
+```
+
+### File old.txt
+### Action delete
+
+### File src/lib.rs
+### Action rename
+### To src/core.rs
+
+#### Change
+**Search**:
+```
+mod a;
+```
+**Content**:
+```
+mod b;
+```
+
+### File assets
+### Action copy
+### To assets-old
+
+### File build
+### Action create-dir
+
+### File tmp
+### Action delete-dir
+</pre>"#;
+
+    #[test]
+    fn markdown_round_trip() {
+        let text = assert_round_trip(MARKDOWN, PlanFormat::Markdown);
+        assert!(text.contains("````\n# Title"));
+        assert_eq!(assert_round_trip(&text, PlanFormat::Markdown), text);
+    }
+
+    #[test]
+    fn xml_round_trip() {
+        let text = assert_round_trip(MARKDOWN, PlanFormat::Xml);
+        assert_eq!(assert_round_trip(&text, PlanFormat::Xml), text);
+    }
+
+    #[test]
+    fn unified_diff_round_trip() {
+        let patch = "\
+diff --git a/src/main.rs b/src/main.rs
+index 1111111..2222222 100644
+--- a/src/main.rs
++++ b/src/main.rs
+@@ -1,3 +1,4 @@ fn main()
+ fn main() {
+-    println!(\"hi\");
++    println!(\"hello\");
++    println!(\"world\");
+ }
+@@ -10,2 +11,0 @@
+-// old
+-// comments
+@@ -20,0 +20,2 @@
++// appended
++// lines
+diff --git a/notes.txt b/notes.txt
+new file mode 100644
+--- /dev/null
++++ b/notes.txt
+@@ -0,0 +1,2 @@
++first
++no newline
+\\ No newline at end of file
+diff --git a/gone.txt b/gone.txt
+deleted file mode 100644
+--- a/gone.txt
++++ /dev/null
+@@ -1 +0,0 @@
+-bye
+diff --git a/a b.txt b/c d.txt
+similarity index 90%
+rename from a b.txt
+rename to c d.txt
+--- a/a b.txt
++++ b/c d.txt
+@@ -1 +1 @@
+-one
++two
+diff --git a/base.rs b/copy.rs
+copy from base.rs
+copy to copy.rs
+--- a/base.rs
++++ b/copy.rs
+@@ -2 +2 @@
+-base
++copy
+diff --git a/run.sh b/run.sh
+old mode 100644
+new mode 100755
+";
+        let text = assert_round_trip(patch, PlanFormat::UnifiedDiff);
+        assert!(text.contains("@@ -1,3 +1,4 @@ fn main()\n"));
+        assert_eq!(assert_round_trip(&text, PlanFormat::UnifiedDiff), text);
+    }
+
+    #[test]
+    fn search_replace_round_trip() {
+        let blocks = "\
+src/app.py
+```python
+<<<<<<< SEARCH
+def main():
+    pass
+=======
+def main():
+    run()  
+>>>>>>> REPLACE
+```
+
+src/app.py
+```python
+<<<<<<< SEARCH
+import os
+=======
+import os
+import sys
+>>>>>>> REPLACE
+```
+
+src/new.py
+```python
+<<<<<<< SEARCH
+=======
+print('new')
+
+>>>>>>> REPLACE
+```
+";
+        let text = assert_round_trip(blocks, PlanFormat::SearchReplace);
+        assert_eq!(assert_round_trip(&text, PlanFormat::SearchReplace), text);
+    }
+
+    // parse -> serialize to another dialect -> parse must give back the same file changes
+    fn assert_converts(input: &str, from: PlanFormat, to: PlanFormat) {
+        let plan = parse_plan(input).unwrap();
+        assert_eq!(plan.format, from);
+        let text = serialize_plan(&plan.file_changes, to).unwrap();
+        let converted = parse_plan(&text).unwrap();
+        assert_eq!(converted.format, to, "{}", text);
+        assert_eq!(converted.file_changes, plan.file_changes, "{}", text);
+        let back = serialize_plan(&converted.file_changes, from).unwrap();
+        assert_eq!(parse_plan(&back).unwrap().file_changes, plan.file_changes);
+    }
+
+    const BLOCKS: &str = "\
+src/app.py
+```python
+<<<<<<< SEARCH
+def main():
+    pass
+=======
+def main():
+    run()
+>>>>>>> REPLACE
+```
+
+src/new.py
+```python
+<<<<<<< SEARCH
+=======
+print('new')
+>>>>>>> REPLACE
+```
+";
+
+    #[test]
+    fn converts_between_dialects() {
+        assert_converts(BLOCKS, PlanFormat::SearchReplace, PlanFormat::Markdown);
+        assert_converts(BLOCKS, PlanFormat::SearchReplace, PlanFormat::Xml);
+        let xml =
+            serialize_plan(&parse_plan(MARKDOWN).unwrap().file_changes, PlanFormat::Xml).unwrap();
+        assert_converts(&xml, PlanFormat::Xml, PlanFormat::Markdown);
+        assert_converts(MARKDOWN, PlanFormat::Markdown, PlanFormat::Xml);
+        let delete = "diff --git a/gone.txt b/gone.txt\ndeleted file mode 100644\n--- a/gone.txt\n+++ /dev/null\n@@ -1 +0,0 @@\n-bye\n";
+        assert_converts(delete, PlanFormat::UnifiedDiff, PlanFormat::Markdown);
+    }
+
+    // Code that looks like the plan's own wrapper and fences
+    const LOOKALIKES: &str = "<pre>
+### File page.html
+### Action modify
+
+#### Change
+**Search**:
+````html
+<pre>
+```
+</pre>
+````
+**Content**:
+````html
+<pre>
+~~~
+```js
+</pre>
+````
+
+### File notes.md
+### Action create
+
+#### Change
+**Content**:
+````
+```
+<pre>code</pre>
+</pre>
+````
+</pre>
+";
+
+    #[test]
+    fn pre_tags_and_fences_in_code_survive_every_dialect() {
+        let file_changes = parse_plan(LOOKALIKES).unwrap().file_changes;
+        assert_eq!(
+            file_changes[0].changes[0].search.as_deref(),
+            Some("<pre>\n```\n</pre>")
+        );
+        assert_eq!(
+            file_changes[1].changes[0].content,
+            "```\n<pre>code</pre>\n</pre>"
+        );
+        for format in [
+            PlanFormat::Markdown,
+            PlanFormat::Xml,
+            PlanFormat::SearchReplace,
+        ] {
+            assert_round_trip(LOOKALIKES, format);
+            assert_converts(LOOKALIKES, PlanFormat::Markdown, format);
+        }
+
+        let patch = "\
+diff --git a/page.html b/page.html
+--- a/page.html
++++ b/page.html
+@@ -1,3 +1,3 @@
+ <pre>
+-```
++~~~
+ </pre>
+diff --git a/notes.md b/notes.md
+new file mode 100644
+--- /dev/null
++++ b/notes.md
+@@ -0,0 +1,2 @@
++```
++</pre>
+";
+        let text = assert_round_trip(patch, PlanFormat::UnifiedDiff);
+        assert_eq!(assert_round_trip(&text, PlanFormat::UnifiedDiff), text);
+        let wrapped = format!("<pre>\n{}</pre>\n", patch);
+        assert_eq!(
+            parse_plan(&wrapped).unwrap().file_changes,
+            parse_plan(patch).unwrap().file_changes
+        );
+        let notes = &parse_plan(patch).unwrap().file_changes[1];
+        assert_eq!(notes.changes[0].content, "```\n</pre>\n");
+    }
+
+    #[test]
+    fn refuses_to_drop_positions_and_file_endings() {
+        let insertion = "--- a/x.txt\n+++ b/x.txt\n@@ -2,0 +3,1 @@\n+inserted\n";
+        let file_changes = parse_plan(insertion).unwrap().file_changes;
+        for format in [
+            PlanFormat::Markdown,
+            PlanFormat::Xml,
+            PlanFormat::SearchReplace,
+        ] {
+            let err = serialize_plan(&file_changes, format).unwrap_err();
+            assert!(err.to_string().contains("x.txt"), "{}", err);
+        }
+        assert!(serialize_plan(&file_changes, PlanFormat::UnifiedDiff).is_ok());
+
+        let file_changes = parse_plan(BLOCKS).unwrap().file_changes;
+        let err = serialize_plan(&file_changes, PlanFormat::UnifiedDiff).unwrap_err();
+        assert!(err.to_string().contains("final newline"), "{}", err);
+        assert!(edit_plan(BLOCKS, &[], Some(PlanFormat::UnifiedDiff)).is_err());
+        assert!(edit_plan(insertion, &[], Some(PlanFormat::Markdown)).is_err());
+    }
+
+    #[test]
+    fn refuses_what_a_dialect_cannot_express() {
+        let file_changes = parse_plan(MARKDOWN).unwrap().file_changes;
+        let err = serialize_plan(&file_changes, PlanFormat::UnifiedDiff).unwrap_err();
+        assert!(err.to_string().contains("Occurrence"), "{}", err);
+        let err = serialize_plan(&file_changes, PlanFormat::SearchReplace).unwrap_err();
+        assert!(err.to_string().contains("occurrence"), "{}", err);
+    }
+
+    #[test]
+    fn edits_and_deletes_changes() {
+        let edits = vec![
+            PlanEdit::EditChange {
+                file_index: 0,
+                change_index: 0,
+                description: Some("Greet properly".to_string()),
+                search: None,
+                content: Some("    println!(\"hey\");".to_string()),
+            },
+            PlanEdit::DeleteChange {
+                file_index: 0,
+                change_index: 1,
+            },
+            PlanEdit::DeleteFile { file_index: 3 },
+        ];
+        let edited = edit_plan(MARKDOWN, &edits, None).unwrap();
+        assert_eq!(edited.format, PlanFormat::Markdown);
+        assert_eq!(edited.files, 7);
+        let file_changes = parse_plan(&edited.plan).unwrap().file_changes;
+        let main = &file_changes[0];
+        assert_eq!(main.changes.len(), 1);
+        assert_eq!(main.changes[0].description, "Greet properly");
+        assert_eq!(
+            main.changes[0].search.as_deref(),
+            Some("    println!(\"hi\");")
+        );
+        assert_eq!(main.changes[0].content, "    println!(\"hey\");");
+        assert!(file_changes.iter().all(|f| f.path != Path::new("old.txt")));
+        assert!(file_changes.iter().enumerate().all(|(i, f)| f.index == i));
+
+        let with_prose = format!("# Plan\nSay hello.\n{}", MARKDOWN);
+        let edited = edit_plan(&with_prose, &[], Some(PlanFormat::Xml)).unwrap();
+        assert!(edited.plan.starts_with("Say hello.\n\n<plan>"));
+        assert_eq!(parse_plan(&edited.plan).unwrap().narrative, "Say hello.");
+
+        let err = edit_plan(MARKDOWN, &[PlanEdit::DeleteFile { file_index: 9 }], None);
+        assert!(err.is_err());
+    }
+}
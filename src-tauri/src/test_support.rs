@@ -0,0 +1,10 @@
+use std::fs;
+use std::path::PathBuf;
+
+// An empty directory under the system temp dir, unique to the test and this run
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("o11n-test-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
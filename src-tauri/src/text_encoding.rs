@@ -0,0 +1,291 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+const UTF8_BOM: &[u8] = &[0xEF, 0xBB, 0xBF];
+const UTF16_LE_BOM: &[u8] = &[0xFF, 0xFE];
+const UTF16_BE_BOM: &[u8] = &[0xFE, 0xFF];
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    Crlf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Utf16le,
+    Utf16be,
+    // Fallback for anything that is not valid UTF-8; every byte round-trips unchanged
+    Latin1,
+}
+
+// How a file is stored on disk. Everything in between read and write works on
+// LF-only Rust strings so Search/Content from the parser match regardless of format.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct TextFormat {
+    pub encoding: Encoding,
+    pub bom: bool,
+    pub line_ending: LineEnding,
+}
+
+impl TextFormat {
+    pub fn decode(bytes: &[u8]) -> Result<(String, TextFormat)> {
+        let (text, encoding, bom) = decode_text(bytes)?;
+        let crlf = text.matches("\r\n").count();
+        let lf = text.matches('\n').count() - crlf;
+        let line_ending = if crlf > lf {
+            LineEnding::Crlf
+        } else {
+            LineEnding::Lf
+        };
+        let format = TextFormat {
+            encoding,
+            bom,
+            line_ending,
+        };
+        Ok((text.replace("\r\n", "\n"), format))
+    }
+
+    // Encodes `text` as the new contents of a file whose bytes were `previous`. When the
+    // file mixed CRLF and LF lines, the lines the edit left alone keep the ending they
+    // had, and only new lines take the majority one.
+    pub fn encode_edit(&self, text: &str, previous: &[u8]) -> Result<Vec<u8>> {
+        let Ok((old, _, _)) = decode_text(previous) else {
+            return self.encode(text);
+        };
+        let crlf = old.matches("\r\n").count();
+        if crlf == 0 || crlf == old.matches('\n').count() {
+            return self.encode(text);
+        }
+        let old_lines: Vec<&str> = old.split_inclusive('\n').collect();
+        let old_keys: Vec<&str> = old_lines.iter().map(|line| line_body(line)).collect();
+        let new_lines: Vec<&str> = text.split_inclusive('\n').collect();
+        let new_keys: Vec<&str> = new_lines.iter().map(|line| line_body(line)).collect();
+        let mut endings: Vec<&str> = vec![self.line_ending.as_str(); new_lines.len()];
+        for op in similar::capture_diff_slices(similar::Algorithm::Myers, &old_keys, &new_keys) {
+            if let similar::DiffOp::Equal {
+                old_index,
+                new_index,
+                len,
+            } = op
+            {
+                for k in 0..len {
+                    let ending = &old_lines[old_index + k][old_keys[old_index + k].len()..];
+                    if !ending.is_empty() {
+                        endings[new_index + k] = ending;
+                    }
+                }
+            }
+        }
+        let mut joined = String::with_capacity(text.len() + crlf);
+        for (line, ending) in new_lines.iter().zip(endings) {
+            joined.push_str(line_body(line));
+            if line.ends_with('\n') {
+                joined.push_str(ending);
+            }
+        }
+        TextFormat {
+            line_ending: LineEnding::Lf,
+            ..*self
+        }
+        .encode(&joined)
+    }
+
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>> {
+        let text = match self.line_ending {
+            LineEnding::Lf => text.to_string(),
+            LineEnding::Crlf => text.replace("\r\n", "\n").replace('\n', "\r\n"),
+        };
+        let mut bytes = Vec::with_capacity(text.len() + 3);
+        match self.encoding {
+            Encoding::Utf8 => {
+                if self.bom {
+                    bytes.extend_from_slice(UTF8_BOM);
+                }
+                bytes.extend_from_slice(text.as_bytes());
+            }
+            Encoding::Utf16le => {
+                if self.bom {
+                    bytes.extend_from_slice(UTF16_LE_BOM);
+                }
+                bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+            }
+            Encoding::Utf16be => {
+                if self.bom {
+                    bytes.extend_from_slice(UTF16_BE_BOM);
+                }
+                bytes.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+            }
+            Encoding::Latin1 => {
+                for c in text.chars() {
+                    let code = u32::from(c);
+                    if code > 0xFF {
+                        return Err(anyhow!(
+                            "Character U+{:04X} cannot be written to a Latin-1 file",
+                            code
+                        ));
+                    }
+                    bytes.push(code as u8);
+                }
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+impl LineEnding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::Crlf => "\r\n",
+        }
+    }
+}
+
+// A line without its line ending
+fn line_body(line: &str) -> &str {
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    line.strip_suffix('\r').unwrap_or(line)
+}
+
+// The text with its line endings as stored, the encoding, and whether a BOM was present
+fn decode_text(bytes: &[u8]) -> Result<(String, Encoding, bool)> {
+    let (encoding, bom, body) = if let Some(body) = bytes.strip_prefix(UTF8_BOM) {
+        (Encoding::Utf8, true, body)
+    } else if let Some(body) = bytes.strip_prefix(UTF16_LE_BOM) {
+        (Encoding::Utf16le, true, body)
+    } else if let Some(body) = bytes.strip_prefix(UTF16_BE_BOM) {
+        (Encoding::Utf16be, true, body)
+    } else if let Some(encoding) = sniff_utf16(bytes) {
+        (encoding, false, bytes)
+    } else if std::str::from_utf8(bytes).is_ok() {
+        (Encoding::Utf8, false, bytes)
+    } else {
+        (Encoding::Latin1, false, bytes)
+    };
+    let text = match encoding {
+        Encoding::Utf8 => String::from_utf8(body.to_vec())?,
+        Encoding::Utf16le => decode_utf16(body, u16::from_le_bytes)?,
+        Encoding::Utf16be => decode_utf16(body, u16::from_be_bytes)?,
+        Encoding::Latin1 => body.iter().map(|&b| b as char).collect(),
+    };
+    Ok((text, encoding, bom))
+}
+
+// UTF-16 without a BOM, recognised by mostly-ASCII text: a zero high byte in most code
+// units and none in the other half. Anything that then fails to decode is left to the
+// other encodings.
+fn sniff_utf16(bytes: &[u8]) -> Option<Encoding> {
+    if bytes.len() < 2 || !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+    let units = bytes.len() / 2;
+    let zeros = |parity: usize| {
+        bytes
+            .iter()
+            .skip(parity)
+            .step_by(2)
+            .filter(|&&b| b == 0)
+            .count()
+    };
+    let (even, odd) = (zeros(0), zeros(1));
+    let encoding = if odd * 2 >= units && even == 0 {
+        Encoding::Utf16le
+    } else if even * 2 >= units && odd == 0 {
+        Encoding::Utf16be
+    } else {
+        return None;
+    };
+    let to_unit = match encoding {
+        Encoding::Utf16le => u16::from_le_bytes,
+        _ => u16::from_be_bytes,
+    };
+    decode_utf16(bytes, to_unit).ok().map(|_| encoding)
+}
+
+fn decode_utf16(bytes: &[u8], to_unit: fn([u8; 2]) -> u16) -> Result<String> {
+    let pairs = bytes.chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return Err(anyhow!("UTF-16 file has an odd number of bytes"));
+    }
+    let units: Vec<u16> = pairs.map(|pair| to_unit([pair[0], pair[1]])).collect();
+    Ok(String::from_utf16(&units)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16(text: &str, to_bytes: fn(u16) -> [u8; 2]) -> Vec<u8> {
+        text.encode_utf16().flat_map(to_bytes).collect()
+    }
+
+    #[test]
+    fn crlf_files_round_trip() {
+        let (text, format) = TextFormat::decode(b"a\r\nb\r\n").unwrap();
+        assert_eq!(text, "a\nb\n");
+        assert_eq!(format.line_ending, LineEnding::Crlf);
+        assert_eq!(format.encode("a\nc\n").unwrap(), b"a\r\nc\r\n");
+    }
+
+    #[test]
+    fn mixed_endings_survive_an_edit() {
+        let previous = b"one\r\ntwo\nthree\r\nfour\n";
+        let (text, format) = TextFormat::decode(previous).unwrap();
+        assert_eq!(text, "one\ntwo\nthree\nfour\n");
+        let edited = text.replace("three", "3").replace("four\n", "four\nfive\n");
+        let bytes = format.encode_edit(&edited, previous).unwrap();
+        assert_eq!(bytes, b"one\r\ntwo\n3\nfour\nfive\n");
+        // Files with a single kind of ending are written as before
+        let (text, format) = TextFormat::decode(b"a\r\n").unwrap();
+        assert_eq!(format.encode_edit(&text, b"a\r\n").unwrap(), b"a\r\n");
+    }
+
+    #[test]
+    fn utf8_bom_is_kept() {
+        let (text, format) = TextFormat::decode(b"\xEF\xBB\xBFhi\n").unwrap();
+        assert_eq!(text, "hi\n");
+        assert!(format.bom);
+        assert_eq!(format.encode("ho\n").unwrap(), b"\xEF\xBB\xBFho\n");
+    }
+
+    #[test]
+    fn utf16_is_detected_with_or_without_bom() {
+        for (bom, to_bytes, encoding) in [
+            (
+                UTF16_LE_BOM,
+                u16::to_le_bytes as fn(u16) -> [u8; 2],
+                Encoding::Utf16le,
+            ),
+            (UTF16_BE_BOM, u16::to_be_bytes, Encoding::Utf16be),
+        ] {
+            let body = utf16("héllo\r\nwörld\r\n", to_bytes);
+            let with_bom = [bom, &body[..]].concat();
+            let (text, format) = TextFormat::decode(&with_bom).unwrap();
+            assert_eq!(text, "héllo\nwörld\n");
+            assert_eq!((format.encoding, format.bom), (encoding, true));
+            assert_eq!(format.encode(&text).unwrap(), with_bom);
+
+            let (text, format) = TextFormat::decode(&body).unwrap();
+            assert_eq!(text, "héllo\nwörld\n");
+            assert_eq!((format.encoding, format.bom), (encoding, false));
+            assert_eq!(format.encode(&text).unwrap(), body);
+        }
+    }
+
+    #[test]
+    fn invalid_utf8_falls_back_to_latin1() {
+        let bytes = b"caf\xE9\n\xFF\n";
+        let (text, format) = TextFormat::decode(bytes).unwrap();
+        assert_eq!(format.encoding, Encoding::Latin1);
+        assert_eq!(text, "café\n\u{FF}\n");
+        assert_eq!(format.encode(&text).unwrap(), bytes);
+        assert!(format.encode("€").is_err());
+    }
+}
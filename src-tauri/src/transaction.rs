@@ -0,0 +1,635 @@
+use crate::apply_file_change::{
+    compute_file_change, read_existing, target_path, write_file_contents,
+};
+use crate::atomic_write::{atomic_write, file_mode, set_mode, temp_path_for};
+use crate::change_types::{Action, ApplyOptions, FileChange, FileOutcome};
+use crate::dir_actions::{copy_path, create_dir, list_tree, move_path};
+use crate::text_encoding::TextFormat;
+use anyhow::{anyhow, Context, Result};
+use log::debug;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Original state of a touched path, captured before anything is written.
+struct Snapshot {
+    path: PathBuf,
+    original: Option<Vec<u8>>,
+    // Permission bits of the original, so a `### Mode` change is rolled back too
+    mode: Option<u32>,
+    created_dirs: Vec<PathBuf>,
+}
+
+// One filesystem operation of the commit, in plan order. Files appear once, at the
+// position they were first touched, and are written with their final staged text.
+enum Step {
+    File(PathBuf),
+    CreateDir(PathBuf),
+    DeleteDir(PathBuf),
+    Copy(PathBuf, PathBuf),
+    // A rename without edits, with the mode the plan asked for
+    Move(PathBuf, PathBuf, Option<u32>),
+}
+
+impl Step {
+    fn path(&self) -> &Path {
+        match self {
+            Step::File(path) | Step::CreateDir(path) | Step::DeleteDir(path) => path,
+            Step::Copy(_, to) | Step::Move(_, to, _) => to,
+        }
+    }
+}
+
+// How to reverse a step that already ran
+enum Undo {
+    Restore(Snapshot),
+    // Something the commit created, plus the parent directories created for it
+    Remove {
+        path: PathBuf,
+        created_dirs: Vec<PathBuf>,
+    },
+    // A deleted directory is only parked next to itself until the commit succeeds
+    Unpark {
+        path: PathBuf,
+        parked: PathBuf,
+    },
+    // Moved back, with the mode it had before when the move changed it
+    Move {
+        from: PathBuf,
+        to: PathBuf,
+        created_dirs: Vec<PathBuf>,
+        mode: Option<u32>,
+    },
+}
+
+// Stages every FileChange in memory and only writes once all of them computed cleanly.
+// If a write fails midway, every path already written is restored to its original bytes.
+#[derive(Default)]
+pub struct Transaction {
+    staged: HashMap<PathBuf, Option<String>>,
+    formats: HashMap<PathBuf, TextFormat>,
+    modes: HashMap<PathBuf, u32>,
+    steps: Vec<Step>,
+    // Directories and copy targets this transaction will create
+    created: HashSet<PathBuf>,
+    // Copy target -> source, so later changes to the copy see the source's text
+    copied_from: HashMap<PathBuf, PathBuf>,
+    // Move target -> source; the source keeps its bytes on disk until the commit
+    moved_from: HashMap<PathBuf, PathBuf>,
+    // Deleted directories and move sources: gone once the steps have run
+    removed: Vec<PathBuf>,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Whether the path exists once every step staged so far has run
+    fn exists(&self, path: &Path) -> bool {
+        if let Some(state) = self.staged.get(path) {
+            return state.is_some();
+        }
+        if self.created.contains(path) {
+            return true;
+        }
+        if let Some(source) = self.move_source(path) {
+            return fs::symlink_metadata(source).is_ok();
+        }
+        if self.is_removed(path) {
+            return false;
+        }
+        fs::symlink_metadata(path).is_ok()
+    }
+
+    fn is_removed(&self, path: &Path) -> bool {
+        self.removed.iter().any(|dir| path.starts_with(dir))
+    }
+
+    // Where a path inside a staged move is still found on disk
+    fn move_source(&self, path: &Path) -> Option<PathBuf> {
+        self.moved_from.iter().find_map(|(to, from)| {
+            let rest = path.strip_prefix(to).ok()?;
+            // Joining an empty path would add a trailing separator
+            Some(if rest.as_os_str().is_empty() {
+                from.clone()
+            } else {
+                from.join(rest)
+            })
+        })
+    }
+
+    // Latest staged text for the path, reading (and remembering the on-disk format of)
+    // the file the first time it is touched
+    fn current_state(&mut self, path: &Path, options: &ApplyOptions) -> Result<Option<String>> {
+        if let Some(state) = self.staged.get(path) {
+            return Ok(state.clone());
+        }
+        if let Some(source) = self.copied_from.get(path).cloned() {
+            let state = self.current_state(&source, options)?;
+            let format = self.formats.get(&source).copied().unwrap_or_default();
+            self.formats.entry(path.to_path_buf()).or_insert(format);
+            return Ok(state);
+        }
+        if let Some(source) = self.move_source(path) {
+            let existing = read_existing(&source)?;
+            let format = existing
+                .as_ref()
+                .map_or(options.new_files, |(_, format)| *format);
+            self.formats.entry(path.to_path_buf()).or_insert(format);
+            return Ok(existing.map(|(text, _)| text));
+        }
+        if self.is_removed(path) {
+            self.formats
+                .entry(path.to_path_buf())
+                .or_insert(options.new_files);
+            return Ok(None);
+        }
+        let existing = read_existing(path)?;
+        let format = existing
+            .as_ref()
+            .map_or(options.new_files, |(_, format)| *format);
+        self.formats.entry(path.to_path_buf()).or_insert(format);
+        Ok(existing.map(|(text, _)| text))
+    }
+
+    fn put(&mut self, path: &Path, contents: Option<String>) {
+        if !self.staged.contains_key(path) {
+            self.steps.push(Step::File(path.to_path_buf()));
+        }
+        self.staged.insert(path.to_path_buf(), contents);
+    }
+
+    pub fn stage(
+        &mut self,
+        file_change: &FileChange,
+        resolved_path: &Path,
+        options: &ApplyOptions,
+    ) -> Result<FileOutcome> {
+        // A file an earlier change already rewrote is renamed with its staged text
+        let staged =
+            self.staged.contains_key(resolved_path) || self.created.contains(resolved_path);
+        if file_change.action.is_tree_action() || (file_change.is_tree_change() && !staged) {
+            let listing = self.stage_tree_action(file_change, resolved_path, options)?;
+            return Ok(FileOutcome {
+                changes: Vec::new(),
+                listing,
+            });
+        }
+        let existing = self.current_state(resolved_path, options)?;
+        let (new_contents, results) =
+            compute_file_change(file_change, resolved_path, existing.as_deref(), options)?;
+        if let Action::Rename = file_change.action {
+            let target = target_path(file_change, resolved_path, options)?;
+            if self.current_state(&target, options)?.is_some() {
+                return Err(anyhow!(
+                    "Refusing to overwrite existing path: {}",
+                    target.display()
+                ));
+            }
+            // The moved file keeps the source's format and permissions
+            let format = self.formats.get(resolved_path).copied().unwrap_or_default();
+            self.formats.insert(target.clone(), format);
+            let mode = file_change
+                .mode
+                .or_else(|| self.modes.get(resolved_path).copied())
+                .or_else(|| file_mode(resolved_path));
+            if let Some(mode) = mode {
+                self.modes.insert(target.clone(), mode);
+            }
+            debug!(
+                "Transaction::stage - Staged rename {} -> {}",
+                resolved_path.display(),
+                target.display()
+            );
+            self.put(&target, new_contents);
+            self.put(resolved_path, None);
+            return Ok(FileOutcome {
+                changes: results,
+                listing: Vec::new(),
+            });
+        }
+        debug!("Transaction::stage - Staged {}", resolved_path.display());
+        self.put(resolved_path, new_contents);
+        if let Some(mode) = file_change.mode {
+            self.modes.insert(resolved_path.to_path_buf(), mode);
+        }
+        Ok(FileOutcome {
+            changes: results,
+            listing: Vec::new(),
+        })
+    }
+
+    fn stage_tree_action(
+        &mut self,
+        file_change: &FileChange,
+        path: &Path,
+        options: &ApplyOptions,
+    ) -> Result<Vec<PathBuf>> {
+        match file_change.action {
+            Action::CreateDir => {
+                if self.exists(path) {
+                    return Err(anyhow!("Path already exists: {}", path.display()));
+                }
+                self.created.insert(path.to_path_buf());
+                self.steps.push(Step::CreateDir(path.to_path_buf()));
+                debug!("Transaction::stage - Staged create-dir {}", path.display());
+                Ok(Vec::new())
+            }
+            Action::DeleteDir => {
+                if !self.exists(path) || !path.is_dir() {
+                    return Err(anyhow!(
+                        "Cannot delete, directory does not exist: {}",
+                        path.display()
+                    ));
+                }
+                // Writes are coalesced per file, so they cannot be ordered around the delete
+                let touched_inside = self
+                    .staged
+                    .keys()
+                    .chain(self.created.iter())
+                    .any(|p| p.starts_with(path));
+                if touched_inside {
+                    return Err(anyhow!(
+                        "Cannot delete directory {}: earlier changes in this plan write inside it",
+                        path.display()
+                    ));
+                }
+                let listing = list_tree(path)?;
+                self.removed.push(path.to_path_buf());
+                self.steps.push(Step::DeleteDir(path.to_path_buf()));
+                debug!("Transaction::stage - Staged delete-dir {}", path.display());
+                Ok(listing)
+            }
+            Action::Copy => {
+                let target = target_path(file_change, path, options)?;
+                if !self.exists(path) {
+                    return Err(anyhow!(
+                        "Cannot copy, path does not exist: {}",
+                        path.display()
+                    ));
+                }
+                if self.exists(&target) {
+                    return Err(anyhow!(
+                        "Refusing to overwrite existing path: {}",
+                        target.display()
+                    ));
+                }
+                let listing = if path.is_dir() {
+                    list_tree(path)?
+                } else {
+                    Vec::new()
+                };
+                self.created.insert(target.clone());
+                self.copied_from.insert(target.clone(), path.to_path_buf());
+                debug!(
+                    "Transaction::stage - Staged copy {} -> {}",
+                    path.display(),
+                    target.display()
+                );
+                self.steps.push(Step::Copy(path.to_path_buf(), target));
+                Ok(listing)
+            }
+            Action::Rename => {
+                let target = target_path(file_change, path, options)?;
+                if !self.exists(path) {
+                    return Err(anyhow!(
+                        "Cannot rename, path does not exist: {}",
+                        path.display()
+                    ));
+                }
+                if self.exists(&target) {
+                    return Err(anyhow!(
+                        "Refusing to overwrite existing path: {}",
+                        target.display()
+                    ));
+                }
+                let touched_inside = self
+                    .staged
+                    .keys()
+                    .chain(self.created.iter())
+                    .any(|p| p.starts_with(path));
+                if touched_inside {
+                    return Err(anyhow!(
+                        "Cannot rename {}: earlier changes in this plan write inside it",
+                        path.display()
+                    ));
+                }
+                let listing = if path.is_dir() {
+                    list_tree(path)?
+                } else {
+                    Vec::new()
+                };
+                self.created.insert(target.clone());
+                self.moved_from.insert(target.clone(), path.to_path_buf());
+                self.removed.push(path.to_path_buf());
+                debug!(
+                    "Transaction::stage - Staged rename {} -> {}",
+                    path.display(),
+                    target.display()
+                );
+                self.steps
+                    .push(Step::Move(path.to_path_buf(), target, file_change.mode));
+                Ok(listing)
+            }
+            _ => Err(anyhow!(
+                "{:?} is not a tree action: {}",
+                file_change.action,
+                path.display()
+            )),
+        }
+    }
+
+    // Runs every staged step. On failure, the returned error names the path that
+    // aborted the commit and all earlier steps have already been rolled back.
+    pub fn commit(self) -> std::result::Result<(), (PathBuf, anyhow::Error)> {
+        let mut undo: Vec<Undo> = Vec::new();
+        for step in &self.steps {
+            if let Err(err) = self.run_step(step, &mut undo) {
+                rollback(&undo);
+                return Err((step.path().to_path_buf(), err));
+            }
+        }
+        for entry in &undo {
+            if let Undo::Unpark { parked, .. } = entry {
+                if let Err(err) = fs::remove_dir_all(parked) {
+                    log::warn!(
+                        "Could not remove deleted directory {}: {}",
+                        parked.display(),
+                        err
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn run_step(&self, step: &Step, undo: &mut Vec<Undo>) -> Result<()> {
+        match step {
+            Step::File(path) => {
+                undo.push(Undo::Restore(take_snapshot(path)?));
+                let contents = self.staged.get(path).cloned().flatten();
+                let format = self.formats.get(path).copied().unwrap_or_default();
+                let mode = self.modes.get(path).copied();
+                write_file_contents(path, contents.as_deref(), &format, mode)
+            }
+            Step::CreateDir(path) => {
+                let created_dirs = missing_ancestors(path);
+                create_dir(path)?;
+                undo.push(Undo::Remove {
+                    path: path.clone(),
+                    created_dirs,
+                });
+                Ok(())
+            }
+            Step::Copy(from, to) => {
+                if fs::symlink_metadata(to).is_ok() {
+                    return Err(anyhow!(
+                        "Refusing to overwrite existing path: {}",
+                        to.display()
+                    ));
+                }
+                // Registered before copying so a partial copy is cleaned up too
+                undo.push(Undo::Remove {
+                    path: to.clone(),
+                    created_dirs: missing_ancestors(to),
+                });
+                copy_path(from, to).map(|_| ())
+            }
+            Step::Move(from, to, mode) => {
+                let created_dirs = missing_ancestors(to);
+                let original_mode = mode.and_then(|_| file_mode(from));
+                move_path(from, to)?;
+                undo.push(Undo::Move {
+                    from: from.clone(),
+                    to: to.clone(),
+                    created_dirs,
+                    mode: original_mode,
+                });
+                match mode {
+                    Some(mode) => set_mode(to, *mode),
+                    None => Ok(()),
+                }
+            }
+            Step::DeleteDir(path) => {
+                let parked = temp_path_for(path)?;
+                fs::rename(path, &parked)
+                    .context(format!("Could not delete directory: {}", path.display()))?;
+                undo.push(Undo::Unpark {
+                    path: path.clone(),
+                    parked,
+                });
+                Ok(())
+            }
+        }
+    }
+}
+
+// Parent directories of `path` that do not exist yet, nearest first
+fn missing_ancestors(path: &Path) -> Vec<PathBuf> {
+    let mut created_dirs = Vec::new();
+    let mut parent = path.parent();
+    while let Some(dir) = parent {
+        if dir.as_os_str().is_empty() || dir.exists() {
+            break;
+        }
+        created_dirs.push(dir.to_path_buf());
+        parent = dir.parent();
+    }
+    created_dirs
+}
+
+fn take_snapshot(path: &Path) -> Result<Snapshot> {
+    let original = if path.exists() {
+        Some(fs::read(path).context(format!("Could not snapshot file: {}", path.display()))?)
+    } else {
+        None
+    };
+    // Remember which parent directories do not exist yet so a rollback can remove them again.
+    Ok(Snapshot {
+        path: path.to_path_buf(),
+        mode: original.as_ref().and_then(|_| file_mode(path)),
+        original,
+        created_dirs: missing_ancestors(path),
+    })
+}
+
+fn undo_step(entry: &Undo) -> (&Path, Result<()>) {
+    match entry {
+        Undo::Restore(snapshot) => {
+            let restored = match &snapshot.original {
+                Some(bytes) => atomic_write(&snapshot.path, bytes, snapshot.mode),
+                None if snapshot.path.exists() => {
+                    fs::remove_file(&snapshot.path).map_err(Into::into)
+                }
+                None => Ok(()),
+            };
+            for dir in &snapshot.created_dirs {
+                let _ = fs::remove_dir(dir);
+            }
+            (&snapshot.path, restored)
+        }
+        Undo::Remove { path, created_dirs } => {
+            let removed = match fs::symlink_metadata(path) {
+                Ok(meta) if meta.is_dir() => fs::remove_dir_all(path).map_err(Into::into),
+                Ok(_) => fs::remove_file(path).map_err(Into::into),
+                Err(_) => Ok(()),
+            };
+            for dir in created_dirs {
+                let _ = fs::remove_dir(dir);
+            }
+            (path, removed)
+        }
+        Undo::Unpark { path, parked } => (path, fs::rename(parked, path).map_err(Into::into)),
+        Undo::Move {
+            from,
+            to,
+            created_dirs,
+            mode,
+        } => {
+            let restored = mode
+                .map_or(Ok(()), |mode| set_mode(to, mode))
+                .and_then(|_| fs::rename(to, from).map_err(Into::into));
+            for dir in created_dirs {
+                let _ = fs::remove_dir(dir);
+            }
+            (from, restored)
+        }
+    }
+}
+
+fn rollback(undo: &[Undo]) {
+    for entry in undo.iter().rev() {
+        let (path, restored) = undo_step(entry);
+        debug!("Transaction::rollback - Restored {}", path.display());
+        if let Err(err) = restored {
+            log::error!(
+                "Failed to restore {} during rollback: {:#}",
+                path.display(),
+                err
+            );
+            sentry::capture_error(err.root_cause());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse_plan::parse_plan;
+    use crate::test_support::scratch_dir;
+
+    fn stage_plan(root: &Path, plan: &str) -> Result<Transaction> {
+        let options = ApplyOptions {
+            project_root: Some(root.to_path_buf()),
+            ..Default::default()
+        };
+        let mut transaction = Transaction::new();
+        for file_change in parse_plan(plan)?.file_changes {
+            transaction.stage(&file_change, &root.join(&file_change.path), &options)?;
+        }
+        Ok(transaction)
+    }
+
+    const EDITS: &str = "### File a.txt\n### Action modify\n#### Change\n**Search**:\n```\none\n```\n**Content**:\n```\n1\n```\n### File new/b.txt\n### Action create\n#### Change\n**Content**:\n```\nb\n```\n### File dir\n### Action create-dir\n";
+
+    #[test]
+    fn commits_every_staged_change() {
+        let root = scratch_dir("transaction-commit");
+        fs::write(root.join("a.txt"), "one\n").unwrap();
+        let plan = format!(
+            "{}### File a.txt\n### Action modify\n#### Change\n**Search**:\n```\n1\n```\n**Content**:\n```\n2\n```\n",
+            EDITS
+        );
+        stage_plan(&root, &plan).unwrap().commit().unwrap();
+        // The second edit of a.txt sees the text staged by the first
+        assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "2\n");
+        assert_eq!(fs::read_to_string(root.join("new/b.txt")).unwrap(), "b\n");
+        assert!(root.join("dir").is_dir());
+    }
+
+    #[test]
+    fn writes_nothing_when_staging_fails() {
+        let root = scratch_dir("transaction-stage");
+        fs::write(root.join("a.txt"), "one\n").unwrap();
+        let plan = format!(
+            "{}### File a.txt\n### Action modify\n#### Change\n**Search**:\n```\nmissing\n```\n**Content**:\n```\nx\n```\n",
+            EDITS
+        );
+        assert!(stage_plan(&root, &plan).is_err());
+        assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "one\n");
+        assert!(!root.join("new").exists());
+        assert!(!root.join("dir").exists());
+    }
+
+    #[test]
+    fn rolls_back_when_a_write_fails() {
+        let root = scratch_dir("transaction-rollback");
+        fs::write(root.join("a.txt"), "one\n").unwrap();
+        fs::create_dir(root.join("old")).unwrap();
+        fs::write(root.join("old/keep.txt"), "keep\n").unwrap();
+        // A file where a directory is needed makes the last write fail
+        fs::write(root.join("blocker"), "").unwrap();
+        let plan = format!(
+            "{}### File old\n### Action delete-dir\n### File blocker/c.txt\n### Action create\n#### Change\n**Content**:\n```\nc\n```\n",
+            EDITS
+        );
+        let (path, _) = stage_plan(&root, &plan).unwrap().commit().unwrap_err();
+        assert_eq!(path, root.join("blocker/c.txt"));
+        assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "one\n");
+        assert!(!root.join("new").exists());
+        assert!(!root.join("dir").exists());
+        assert_eq!(
+            fs::read_to_string(root.join("old/keep.txt")).unwrap(),
+            "keep\n"
+        );
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rolls_back_mode_changes() {
+        let root = scratch_dir("transaction-mode");
+        fs::write(root.join("run.sh"), "echo\n").unwrap();
+        set_mode(&root.join("run.sh"), 0o644).unwrap();
+        fs::write(root.join("blocker"), "").unwrap();
+        let plan = "### File run.sh\n### Action rewrite\n### Mode executable\n#### Change\n**Content**:\n```\n#!/bin/sh\necho\n```\n### File blocker/c.txt\n### Action create\n#### Change\n**Content**:\n```\nc\n```\n";
+        assert!(stage_plan(&root, plan).unwrap().commit().is_err());
+        assert_eq!(fs::read_to_string(root.join("run.sh")).unwrap(), "echo\n");
+        assert_eq!(
+            file_mode(&root.join("run.sh")).map(|mode| mode & 0o777),
+            Some(0o644)
+        );
+    }
+
+    #[test]
+    fn renames_move_bytes_and_roll_back() {
+        let root = scratch_dir("transaction-rename");
+        let bytes = b"\x89PNG\r\n\x1a\n\0\x0a\xff".to_vec();
+        fs::write(root.join("logo.png"), &bytes).unwrap();
+        fs::create_dir(root.join("src")).unwrap();
+        fs::write(root.join("src/a.txt"), "one\n").unwrap();
+        let renames = "### File logo.png\n### Action rename\n### To img/logo.png\n### File src\n### Action rename\n### To lib\n### File lib/a.txt\n### Action modify\n#### Change\n**Search**:\n```\none\n```\n**Content**:\n```\n1\n```\n### File b.txt\n### Action rename\n### To c.txt\n### File c.txt\n### Action modify\n#### Change\n**Search**:\n```\ntwo\n```\n**Content**:\n```\n2\n```\n";
+        fs::write(root.join("b.txt"), "two\n").unwrap();
+
+        fs::write(root.join("blocker"), "").unwrap();
+        let failing = format!(
+            "{}### File blocker/c.txt\n### Action create\n#### Change\n**Content**:\n```\nc\n```\n",
+            renames
+        );
+        assert!(stage_plan(&root, &failing).unwrap().commit().is_err());
+        assert_eq!(fs::read(root.join("logo.png")).unwrap(), bytes);
+        assert_eq!(fs::read_to_string(root.join("src/a.txt")).unwrap(), "one\n");
+        assert!(!root.join("img").exists());
+        assert!(!root.join("lib").exists());
+
+        fs::remove_file(root.join("blocker")).unwrap();
+        stage_plan(&root, renames).unwrap().commit().unwrap();
+        assert_eq!(fs::read(root.join("img/logo.png")).unwrap(), bytes);
+        assert_eq!(fs::read_to_string(root.join("lib/a.txt")).unwrap(), "1\n");
+        assert!(!root.join("logo.png").exists());
+        assert!(!root.join("src").exists());
+        assert_eq!(fs::read_to_string(root.join("c.txt")).unwrap(), "2\n");
+        assert!(!root.join("b.txt").exists());
+    }
+}
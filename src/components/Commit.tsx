@@ -1,5 +1,6 @@
 import { useState } from "react";
 import { invoke } from "@tauri-apps/api/core";
+import { ask } from "@tauri-apps/plugin-dialog";
 import {
   BaseDirectory,
   readTextFile,
@@ -10,7 +11,12 @@ import { useUserContext } from "../context/UserContext";
 import { CircularProgress, Grid } from "@mui/material";
 import { Create } from "@mui/icons-material";
 import RetroButton from "./RetroButton";
-import type { ErrorReport, FileNode, SuccessReport } from "../types";
+import type {
+  ApplyReport,
+  ErrorReport,
+  FileNode,
+  PlanSummary,
+} from "../types";
 import useShortcut from "../utils/useShortcut";
 import Toast from "./Toast";
 import { formatWithPrettier } from "../utils/formatWithPrettier";
@@ -19,6 +25,7 @@ const Commit = () => {
   const [committing, setCommitting] = useState(false);
   const [commitFailed, setCommitFailed] = useState(false);
   const [toastOpen, setToastOpen] = useState(false);
+  const [toastMessage, setToastMessage] = useState("");
   const handleToastClose = () => {
     setToastOpen(false);
   };
@@ -42,6 +49,79 @@ const Commit = () => {
     );
   };
 
+  // The project an absolute path lies in; the deepest one wins for nested projects
+  const projectOf = (path: string) =>
+    projects
+      .map((p) => p.path)
+      .filter(
+        (root) =>
+          path.startsWith(root) && /^[\\/]?$/.test(path.charAt(root.length)),
+      )
+      .sort((a, b) => b.length - a.length)[0];
+
+  // Applies the plan (or the files in `include`) against one project root. Protected
+  // files the backend held back are applied in a second pass once the user confirms them.
+  const applyToProject = async (
+    planToApply: string,
+    projectRoot: string | undefined,
+    include?: string[],
+  ): Promise<ApplyReport[]> => {
+    const report = await invoke<ApplyReport>("apply_protocol", {
+      xmlInput: planToApply,
+      options: { projectRoot, include },
+    });
+    if (report.blocked.length === 0) return [report];
+    const listing = report.blocked
+      .map((file) => `${file.path}: ${file.reason}`)
+      .join("\n");
+    const confirmed = await ask(
+      `These protected files were not applied:\n${listing}\n\nApply them anyway?`,
+      { title: "Protected files", kind: "warning" },
+    );
+    if (!confirmed) return [report];
+    const retry = await invoke<ApplyReport>("apply_protocol", {
+      xmlInput: planToApply,
+      options: {
+        projectRoot,
+        include: report.blocked.map((file) => file.id),
+        confirmedPaths: report.blocked.flatMap((file) => file.confirm),
+      },
+    });
+    return [{ ...report, blocked: [] }, retry];
+  };
+
+  // Applies each file against its own project's root: the backend sandboxes a plan to
+  // a single root, so a plan spanning projects is applied one project at a time
+  const applyByProject = async (
+    planToApply: string,
+    defaultRoot: string | undefined,
+  ): Promise<ApplyReport[]> => {
+    const groups = new Map<string | undefined, string[]>();
+    try {
+      const summary = await invoke<PlanSummary>("summarize_protocol", {
+        xmlInput: planToApply,
+      });
+      for (const file of summary.files) {
+        const root = projectOf(file.path) ?? defaultRoot;
+        groups.set(root, [...(groups.get(root) ?? []), file.id]);
+      }
+    } catch (e) {
+      // Let apply report what is wrong with the plan
+      console.error("Could not summarize plan", e);
+    }
+    if (groups.size <= 1) {
+      const [projectRoot = defaultRoot] = groups.keys();
+      return applyToProject(planToApply, projectRoot);
+    }
+    const reports: ApplyReport[] = [];
+    for (const [projectRoot, include] of groups) {
+      reports.push(
+        ...(await applyToProject(planToApply, projectRoot, include)),
+      );
+    }
+    return reports;
+  };
+
   const handleCommit = async () => {
     setCommitting(true);
     // Filter plan according to selected descriptions
@@ -79,12 +159,16 @@ const Commit = () => {
       for (const fileMatch of planToApply.matchAll(filePathRegex)) {
         changedFilePaths.push(fileMatch[1].trim());
       }
-      const { errors, success } = await invoke<{
-        errors: ErrorReport[];
-        success: SuccessReport[];
-      }>("apply_protocol", {
-        xmlInput: planToApply,
-      });
+      // Relative plan paths resolve against the project the selected files belong to
+      const defaultRoot =
+        selectedFiles.find((f) => f.projectRoot)?.projectRoot ??
+        projects[0]?.path;
+      const reports = await applyByProject(planToApply, defaultRoot);
+      const success = reports.flatMap((r) => r.success);
+      const errors = reports.flatMap((r) => r.errors);
+      const blocked = reports.flatMap((r) => r.blocked);
+      const stale = reports.flatMap((r) => r.stale);
+      const aborted = reports.find((r) => r.aborted)?.aborted;
       for (const file of success) {
         try {
           const fileContent = await readTextFile(file.path);
@@ -101,8 +185,36 @@ const Commit = () => {
           console.error("Prettier format failed for", file.path, e);
         }
       }
+      // Undo compares files against the journal, which must see the formatted text
+      for (const { journalId } of reports) {
+        if (!journalId) continue;
+        try {
+          await invoke("refresh_apply_journal", { id: journalId });
+        } catch (e) {
+          console.error("Could not update apply journal", journalId, e);
+        }
+      }
+      // Protected files the apply held back are listed with the errors so none go
+      // missing; stale files were still applied, so they only get a note
+      const skipped: ErrorReport[] = [
+        ...(aborted ? [aborted] : []),
+        ...blocked.map((file) => ({
+          path: file.path,
+          messages: ["Not applied", file.reason],
+        })),
+      ];
       setFileSuccesses(success);
-      setErrorReports(errors);
+      setErrorReports([...errors, ...skipped]);
+      const notes = [
+        aborted && "Nothing was applied: the transaction was aborted",
+        blocked.length > 0 &&
+          `Skipped protected files: ${blocked.map((f) => f.path).join(", ")}`,
+        stale.length > 0 &&
+          `Applied over edits made since the prompt: ${stale.map((f) => f.path).join(", ")}`,
+      ].filter(Boolean);
+      setToastMessage(
+        notes.length > 0 ? notes.join(". ") : "Changes applied successfully!",
+      );
     } catch (error) {
       console.error("Failed to apply changes:", error);
       commitError = true;
@@ -192,7 +304,7 @@ const Commit = () => {
       </Grid>
       <Toast
         open={toastOpen}
-        message="Changes applied successfully!"
+        message={toastMessage}
         onClose={handleToastClose}
       />
     </>
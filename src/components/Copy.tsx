@@ -109,6 +109,16 @@ export default function Copy() {
         // Only include non-image files (as in original computePrompt)
         if (markdownExtension !== "image") {
           lines.push(`**File:** ${file.path}`);
+          if (formatOutput) {
+            try {
+              const hash = await invoke("content_hash_path", {
+                path: file.path,
+              });
+              lines.push(`**Hash:** ${hash}`);
+            } catch (err) {
+              console.error("Failed to hash file:", err);
+            }
+          }
           lines.push(`\`\`\`${markdownExtension}`);
           lines.push(content);
           lines.push("```");
@@ -1,12 +1,124 @@
+export interface ChangeResult {
+  id: string;
+  fileIndex: number;
+  changeIndex: number;
+  description: string;
+  status: "applied" | "matched" | "failed" | "skipped" | "excluded";
+  matches: { start: number; end: number }[];
+  message?: string;
+}
+
+export interface Diagnostic {
+  line: number;
+  column: number;
+  endColumn: number;
+  severity: "error" | "warning";
+  code: string;
+  message: string;
+}
+
+export type PlanFormat = "markdown" | "unified-diff" | "search-replace" | "xml";
+
+export interface ValidationReport {
+  format: PlanFormat;
+  valid: boolean;
+  files: number;
+  diagnostics: Diagnostic[];
+}
+
+// `id` and `changeIds` are what a selective apply lists in `include`
+export interface FileSummary {
+  id: string;
+  index: number;
+  path: string;
+  action: string;
+  target?: string;
+  descriptions: string[];
+  changes: number;
+  changeIds: string[];
+}
+
+export interface PlanSummary {
+  format: PlanFormat;
+  narrative: string;
+  files: FileSummary[];
+  // Files per action, e.g. { create: 2, modify: 1 }
+  actions: Record<string, number>;
+  changes: number;
+  warnings: Diagnostic[];
+}
+
+// A plan found in pasted text; pass its index as `planIndex` to apply or preview it
+export interface CandidatePlan {
+  index: number;
+  start: number;
+  end: number;
+  startLine: number;
+  endLine: number;
+  text: string;
+  format: PlanFormat;
+  valid: boolean;
+  files: number;
+  diagnostics: Diagnostic[];
+}
+
+// Indices are the fileIndex/changeIndex of the plan as parsed
+export type PlanEdit =
+  | { op: "delete-file"; fileIndex: number }
+  | { op: "delete-change"; fileIndex: number; changeIndex: number }
+  | {
+      op: "edit-change";
+      fileIndex: number;
+      changeIndex: number;
+      description?: string;
+      search?: string;
+      content?: string;
+    };
+
+export interface EditedPlan {
+  format: PlanFormat;
+  plan: string;
+  files: number;
+}
+
 export interface ErrorReport {
   path: string;
   messages: string[];
+  changes?: ChangeResult[];
 }
 
 export interface SuccessReport {
   path: string;
   messages: string[];
+  changes?: ChangeResult[];
+  listing?: string[];
+}
+// A protected file the apply skipped; resend `confirm` in `confirmedPaths` (and `id` in
+// `include`) to apply it
+export interface BlockedFile {
+  id: string;
+  path: string;
+  reason: string;
+  confirm: string[];
+}
+
+// A file edited since the prompt was generated
+export interface StaleFile {
+  path: string;
+  expected: string;
+  actual: string | null;
 }
+
+export interface ApplyReport {
+  success: SuccessReport[];
+  errors: ErrorReport[];
+  aborted?: ErrorReport;
+  journalId: string | null;
+  blocked: BlockedFile[];
+  stale: StaleFile[];
+  warnings: Diagnostic[];
+}
+
 export interface FileNode {
   id: string;
   name: string;